@@ -2,10 +2,10 @@ extern crate argparse;
 extern crate image;
 extern crate path_tracer;
 
-use argparse::{ArgumentParser, Store, Collect};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue, Collect};
 use path_tracer::*;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, BufWriter};
 use std::io::prelude::*;
 
 #[derive(Debug)]
@@ -93,11 +93,23 @@ fn load_file(name: &String) -> Result<PartialImage> {
 fn main() {
     let mut to_merge: Vec<String> = Vec::new();
     let mut output_filename = "image.png".to_string();
+    let mut float_exr = false;
+    let mut tone_curve = ToneCurve::Clamp;
+    let mut exposure = 0.0;
+    let mut gamma: Option<f64> = None;
     {
         let mut ap = ArgumentParser::new();
-        ap.set_description("Combine several sample images into one PNG");
+        ap.set_description("Combine several sample images into one image");
         ap.refer(&mut output_filename).add_option(&["-o", "--output"], Store,
-                                                  "Filename to output to");
+                                                  "Filename to output to (.png, .pfm, .hdr or .exr)");
+        ap.refer(&mut float_exr).add_option(&["--float-exr"], StoreTrue,
+                                            "Write 32-bit float channels to EXR files, rather than half floats");
+        ap.refer(&mut tone_curve).add_option(&["--tonemap"], Store,
+                                             "Tone curve for PNG output: clamp, reinhard, filmic or aces");
+        ap.refer(&mut exposure).add_option(&["--exposure"], Store,
+                                           "Exposure adjustment for PNG output, in stops");
+        ap.refer(&mut gamma).add_option(&["--gamma"], StoreOption,
+                                        "Encode PNG output with this gamma rather than the sRGB curve");
         ap.refer(&mut to_merge).add_argument("files", Collect, "Files to merge")
             .required();
         ap.parse_args_or_exit();
@@ -112,11 +124,22 @@ fn main() {
     let height = accum.height();
     let width = accum.width();
     let samples = accum.samples;
+    let exr_pixel_type = if float_exr { ExrPixelType::Float } else { ExrPixelType::Half };
+    if let Some(format) = HdrFormat::from_filename(&output_filename, exr_pixel_type) {
+        let average: Vec<Vec<Vec3d>> = accum.image.iter()
+            .map(|row| row.iter().map(|&sum| sum / samples as f64).collect())
+            .collect();
+        let mut writer = BufWriter::new(File::create(output_filename).unwrap());
+        write_hdr_image(&mut writer, format, &average).unwrap();
+        return;
+    }
+    let tone_mapper = ToneMapper::new(tone_curve, exposure,
+                                      gamma.map_or(TransferFunction::Srgb, TransferFunction::Gamma));
     let mut image = image::ImageBuffer::new(width as u32, height as u32);
     for y in 0..height {
         for x in 0..width {
             let sum = accum.image[y][x] / samples as f64;
-            image.put_pixel(x as u32, y as u32, image::Rgb([to_int(sum.x), to_int(sum.y), to_int(sum.z)]));
+            image.put_pixel(x as u32, y as u32, image::Rgb(tone_mapper.to_rgb8(sum)));
         }
     }
     let mut output_file = File::create(output_filename).unwrap();
@@ -6,13 +6,14 @@ extern crate num_cpus;
 extern crate rand;
 extern crate threadpool;
 
-use argparse::{ArgumentParser, Store, StoreTrue};
+use argparse::{ArgumentParser, Collect, Store, StoreOption, StoreTrue};
 use rand::{XorShiftRng, SeedableRng};
 use threadpool::ThreadPool;
 
 use std::fs::File;
 use std::io::{self, BufWriter};
 use std::io::prelude::*;
+use std::process;
 use std::sync::Arc;
 use std::sync::mpsc::channel;
 
@@ -22,72 +23,100 @@ use path_tracer::*;
 
 
 fn main() {
-    let mut samps = 1;
-    let mut width = 1024;
-    let mut height = 768;
+    let mut scene_filename = "".to_string();
+    let mut samps: Option<usize> = None;
+    let mut width: Option<usize> = None;
+    let mut height: Option<usize> = None;
     let mut output_filename = "".to_string();
     let mut num_threads = num_cpus::get();
     let mut seed = 0x193a6754;
     let mut partial = false;
+    let mut obj_files: Vec<String> = Vec::new();
+    let mut float_exr = false;
+    let mut tone_curve = ToneCurve::Clamp;
+    let mut exposure = 0.0;
+    let mut gamma: Option<f64> = None;
+    let mut min_depth: Option<usize> = None;
+    let mut max_depth: Option<usize> = None;
+    let mut roulette_depth: Option<usize> = None;
+    let mut light_samples: Option<usize> = None;
     {
         let mut ap = ArgumentParser::new();
-        ap.set_description("Render a simple image");
-        ap.refer(&mut samps).add_option(&["-s", "--samples"], Store, "Number of samples");
-        ap.refer(&mut height).add_option(&["-h", "--height"], Store, "Height");
-        ap.refer(&mut width).add_option(&["-w", "--width"], Store, "Width");
+        ap.set_description("Render a scene file");
+        ap.refer(&mut scene_filename).add_argument("scene", Store, "Scene file to render")
+            .required();
+        ap.refer(&mut samps).add_option(&["-s", "--samples"], StoreOption,
+                                        "Number of samples (overrides the scene file)");
+        ap.refer(&mut height).add_option(&["-h", "--height"], StoreOption,
+                                         "Height (overrides the scene file)");
+        ap.refer(&mut width).add_option(&["-w", "--width"], StoreOption,
+                                        "Width (overrides the scene file)");
+        ap.refer(&mut min_depth).add_option(&["--min-depth"], StoreOption,
+                                            "Only record light that has bounced at least this many times");
+        ap.refer(&mut max_depth).add_option(&["--max-depth"], StoreOption,
+                                            "Maximum number of bounces (overrides the scene file)");
+        ap.refer(&mut roulette_depth).add_option(&["--roulette-depth"], StoreOption,
+                                                 "Bounce at which to start Russian roulette (overrides the scene file)");
+        ap.refer(&mut light_samples).add_option(&["--light-samples"], StoreOption,
+                                                "Lights to sample per bounce, chosen by power; 0 samples them all");
         ap.refer(&mut output_filename).add_option(&["-o", "--output"], Store,
-                                                  "Filename to output to");
+                                                  "Filename to output to (.png, .pfm, .hdr or .exr)");
+        ap.refer(&mut float_exr).add_option(&["--float-exr"], StoreTrue,
+                                            "Write 32-bit float channels to EXR files, rather than half floats");
+        ap.refer(&mut tone_curve).add_option(&["--tonemap"], Store,
+                                             "Tone curve for PNG output: clamp, reinhard, filmic or aces");
+        ap.refer(&mut exposure).add_option(&["--exposure"], Store,
+                                           "Exposure adjustment for PNG output, in stops");
+        ap.refer(&mut gamma).add_option(&["--gamma"], StoreOption,
+                                        "Encode PNG output with this gamma rather than the sRGB curve");
         ap.refer(&mut num_threads).add_option(&["--num-threads"], Store,
                                               "Number of threads to use");
         ap.refer(&mut seed).add_option(&["--seed"], Store, "Random seed");
         ap.refer(&mut partial).add_option(&["--partial"], StoreTrue,
                                           "Output a partial render");
+        ap.refer(&mut obj_files).add_option(&["--obj"], Collect,
+                                            "Wavefront OBJ file to add to the scene");
         ap.parse_args_or_exit();
     }
-    samps = samps / 4;
+    let description = match load_scene(&scene_filename) {
+        Ok(description) => description,
+        Err(e) => {
+            println!("Unable to load scene: {}", e);
+            process::exit(1);
+        }
+    };
+    let width = width.unwrap_or(description.settings.width);
+    let height = height.unwrap_or(description.settings.height);
+    if width == 0 || height == 0 {
+        println!("The image needs a width and height of at least 1");
+        process::exit(1);
+    }
+    let mut samps = samps.unwrap_or(description.settings.samples) / 4;
     if samps < 1 { samps = 1; }
+    let mut settings = description.settings;
+    settings.min_depth = min_depth.unwrap_or(settings.min_depth);
+    settings.max_depth = max_depth.unwrap_or(settings.max_depth);
+    settings.roulette_depth = roulette_depth.unwrap_or(settings.roulette_depth);
+    settings.light_samples = light_samples.unwrap_or(settings.light_samples);
     if output_filename == "" {
         output_filename = if partial { "image.part" } else { "image.png" }.to_string();
     }
-    const BLACK: Vec3d = Vec3d { x: 0.0, y: 0.0, z: 0.0 };
-    const RED: Vec3d = Vec3d { x: 0.75, y: 0.25, z: 0.25 };
-    const BLUE: Vec3d = Vec3d { x: 0.25, y: 0.25, z: 0.75 };
-    const GREY: Vec3d = Vec3d { x: 0.75, y: 0.75, z: 0.75 };
-    const WHITE: Vec3d = Vec3d { x: 0.999, y: 0.999, z: 0.999 };
-    let mut scene = Scene::new();
-    scene.add(Box::new(Sphere::new(Material::Diffuse, 1e5,
-                                   Vec3d::new(1e5 + 1.0, 40.8, 81.6),
-                                   BLACK, RED)));
-    scene.add(Box::new(Sphere::new(Material::Diffuse, 1e5,
-                                   Vec3d::new(-1e5 + 99.0, 40.8, 81.6),
-                                   BLACK, BLUE)));
-    scene.add(Box::new(Sphere::new(Material::Diffuse, 1e5,
-                                   Vec3d::new(50.0, 40.8, 1e5),
-                                   BLACK, GREY)));
-    scene.add(Box::new(Sphere::new(Material::Diffuse, 1e5,
-                                   Vec3d::new(50.0, 40.8, -1e5 + 170.0),
-                                   BLACK, BLACK)));
-    scene.add(Box::new(Sphere::new(Material::Diffuse, 1e5,
-                                   Vec3d::new(50.0, 1e5, 81.6),
-                                   BLACK, GREY)));
-    scene.add(Box::new(Sphere::new(Material::Diffuse, 1e5,
-                                   Vec3d::new(50.0, -1e5 + 81.6, 81.6),
-                                   BLACK, GREY)));
-    scene.add(Box::new(Sphere::new(Material::Specular, 16.5,
-                                   Vec3d::new(27.0, 16.5, 47.0),
-                                   BLACK, WHITE)));
-    scene.add(Box::new(Sphere::new(Material::Refractive, 16.5,
-                                   Vec3d::new(73.0, 16.5, 78.0),
-                                   BLACK, WHITE)));
-    scene.add(Box::new(Sphere::new(Material::Diffuse, 1.5,
-                                   Vec3d::new(50.0, 81.6 - 16.5, 81.6),
-                                   Vec3d::new(400.0, 400.0, 400.0), BLACK)));
+    let exr_pixel_type = if float_exr { ExrPixelType::Float } else { ExrPixelType::Half };
+    let hdr_format = if partial { None } else { HdrFormat::from_filename(&output_filename, exr_pixel_type) };
+    let tone_mapper = ToneMapper::new(tone_curve, exposure,
+                                      gamma.map_or(TransferFunction::Srgb, TransferFunction::Gamma));
+    let mut scene = description.scene;
+    for obj_file in obj_files.iter() {
+        if let Err(e) = load_obj(obj_file, &mut scene) {
+            println!("Unable to load '{}': {}", obj_file, e);
+            process::exit(1);
+        }
+    }
+    scene.build_bvh();
+    scene.build_light_distribution();
     let scene = Arc::new(scene);
 
-    let camera_pos = Vec3d::new(50.0, 52.0, 295.6);
-    let camera_dir = Vec3d::new(0.0, -0.042612, -1.0).normalized();
-    let camera_x = Vec3d::new(width as f64 * 0.5135 / height as f64, 0.0, 0.0);
-    let camera_y = camera_x.cross(camera_dir).normalized() * 0.5135;
+    let camera: Arc<Box<Camera>> = Arc::new(description.camera.camera(width as f64 / height as f64));
 
     println!("Using {} threads", num_threads);
     let pool = ThreadPool::new(num_threads);
@@ -96,6 +125,7 @@ fn main() {
     for y in 0..height {
         let tx = tx.clone();
         let scene = scene.clone();
+        let camera = camera.clone();
         pool.execute(move || {
             let mut line = Vec::with_capacity(width);
             let mut rng = XorShiftRng::from_seed([1 + (y * y) as u32, seed, 0x15aac60d, 0xb017f00d]);
@@ -108,15 +138,14 @@ fn main() {
                             let dx = random_samp(&mut rng);
                             let dy = random_samp(&mut rng);
                             let sub_x = (sx as f64 + 0.5 + dx) / 2.0;
-                            let dir_x = (sub_x + x as f64) / width as f64 - 0.5;
+                            let film_x = (sub_x + x as f64) / width as f64;
                             let sub_y = (sy as f64 + 0.5 + dy) / 2.0;
-                            let dir_y = (sub_y + (height - y - 1) as f64) / height as f64 - 0.5;
-                            let dir = (camera_x * dir_x + camera_y * dir_y + camera_dir).normalized();
-                            let jittered_ray = Ray::new(camera_pos + dir * 140.0, dir);
-                            let sample = radiance(&scene, &jittered_ray, 0, &mut rng, true);
+                            let film_y = 1.0 - (sub_y + (height - y - 1) as f64) / height as f64;
+                            let jittered_ray = camera.generate_ray(film_x, film_y, &mut rng);
+                            let sample = radiance(&scene, &jittered_ray, &settings, &mut rng);
                             r = r + (sample / samps as f64);
                         }
-                        sum = sum + r.clamp() * 0.25;
+                        sum = sum + r * 0.25;
                     }
                 }
                 line.push(sum);
@@ -136,13 +165,17 @@ fn main() {
         screen[y] = line;
         left -= 1;
     }
-    if !partial {
+    if let Some(format) = hdr_format {
+        println!("\nWriting output to '{}'", output_filename);
+        let mut writer = BufWriter::new(File::create(output_filename).unwrap());
+        write_hdr_image(&mut writer, format, &screen).unwrap();
+    } else if !partial {
         println!("\nWriting output to '{}'", output_filename);
         let mut image = image::ImageBuffer::new(width as u32, height as u32);
         for y in 0..height {
             for x in 0..width {
                 let sum = screen[y][x];
-                image.put_pixel(x as u32, y as u32, image::Rgb([to_int(sum.x), to_int(sum.y), to_int(sum.z)]));
+                image.put_pixel(x as u32, y as u32, image::Rgb(tone_mapper.to_rgb8(sum)));
             }
         }
         let mut output_file = File::create(output_filename).unwrap();
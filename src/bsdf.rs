@@ -0,0 +1,87 @@
+// The interface between surfaces and the integrator. A BSDF describes how light arriving from
+// one direction is scattered into another; `radiance` only ever talks to materials through this
+// trait, so new materials can be added (even outside this crate) without touching it.
+//
+// All directions are normalized and point away from the surface: `wo` back along the incoming
+// ray, and `wi` towards wherever light is gathered from.
+use math::{Onb, Vec3d, F64Rng};
+use medium::Medium;
+
+use std::ops::BitOr;
+
+// Describes the lobes of a BSDF, or the one a sample was drawn from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BsdfFlags(u32);
+
+impl BsdfFlags {
+    pub const NONE: BsdfFlags = BsdfFlags(0);
+    pub const REFLECTION: BsdfFlags = BsdfFlags(1);
+    pub const TRANSMISSION: BsdfFlags = BsdfFlags(2);
+    pub const DIFFUSE: BsdfFlags = BsdfFlags(4);
+    pub const GLOSSY: BsdfFlags = BsdfFlags(8);
+    // A lobe concentrated in a single direction, like a perfect mirror. These can't be evaluated
+    // for an arbitrary pair of directions, only sampled.
+    pub const DELTA: BsdfFlags = BsdfFlags(16);
+    // An invisible surface that light passes straight through, such as the edge of a medium.
+    // Paths and shadow rays carry on through these without counting a bounce.
+    pub const NULL: BsdfFlags = BsdfFlags(32);
+
+    pub fn contains(self, other: BsdfFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for BsdfFlags {
+    type Output = BsdfFlags;
+    fn bitor(self, other: BsdfFlags) -> BsdfFlags {
+        BsdfFlags(self.0 | other.0)
+    }
+}
+
+// What a BSDF knows about the point being shaded.
+#[derive(Debug, Clone, Copy)]
+pub struct BsdfContext {
+    // The shading frame: w is the surface normal, pointing out of the object whichever side the
+    // ray arrived on, and u follows the surface's u direction where it has one, for materials
+    // that aren't the same all the way around the normal.
+    pub frame: Onb,
+    // The index of refraction of whatever is on the outside of the surface.
+    pub outside_ior: f64,
+    // Where the point is, as surface coordinates and in space, for looking up textures.
+    pub uv: (f64, f64),
+    pub pos: Vec3d
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BsdfSample {
+    pub direction: Vec3d,
+    // f(wo, wi) |cos(wi)| / pdf: what to multiply the light arriving from `direction` by.
+    pub weight: Vec3d,
+    // For delta lobes, this is just the probability of having picked the lobe.
+    pub pdf: f64,
+    pub flags: BsdfFlags
+}
+
+// The stuff inside a closed surface that light can pass into.
+#[derive(Debug, Clone, Copy)]
+pub struct Interior {
+    pub ior: f64,
+    // Light travelling inside is attenuated by exp(-absorption * distance) in each channel.
+    pub absorption: Vec3d,
+    // Anything inside that scatters light.
+    pub medium: Option<Medium>
+}
+
+pub trait Bsdf: Send + Sync {
+    fn flags(&self) -> BsdfFlags;
+    // The value of the BSDF for light arriving from wi and leaving towards wo. Delta lobes
+    // contribute nothing here.
+    fn eval(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> Vec3d;
+    // Picks an incoming direction given the outgoing one, or None if the light is absorbed.
+    fn sample(&self, context: &BsdfContext, wo: Vec3d, rng: &mut F64Rng) -> Option<BsdfSample>;
+    // The density with which `sample` would pick wi, with respect to solid angle.
+    fn pdf(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> f64;
+    // Materials that transmit light describe what's inside them, so the integrator can keep
+    // track of which medium each ray is travelling through.
+    fn interior(&self) -> Option<Interior> { None }
+}
@@ -0,0 +1,226 @@
+// Bounding volume hierarchy, built with a binned surface area heuristic. The hierarchy only
+// knows about the bounding boxes of its primitives: callers own the primitives themselves and
+// supply a closure to intersect one by index.
+use geometry::Ray;
+use math::Vec3d;
+
+use std::f64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3d,
+    pub max: Vec3d
+}
+
+impl Aabb {
+    pub fn new(min: Vec3d, max: Vec3d) -> Aabb {
+        Aabb { min: min, max: max }
+    }
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3d::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Vec3d::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY)
+        }
+    }
+    pub fn from_points(points: &[Vec3d]) -> Aabb {
+        points.iter().fold(Aabb::empty(), |b, &p| b.include(p))
+    }
+    pub fn include(self, point: Vec3d) -> Aabb {
+        Aabb { min: self.min.min(point), max: self.max.max(point) }
+    }
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb { min: self.min.min(other.min), max: self.max.max(other.max) }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+    pub fn centre(&self) -> Vec3d {
+        (self.min + self.max) * 0.5
+    }
+    pub fn extent(&self) -> Vec3d {
+        self.max - self.min
+    }
+    pub fn surface_area(&self) -> f64 {
+        if self.is_empty() { return 0.0; }
+        let e = self.extent();
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+    // Slab test. Returns the distance at which the ray enters the box, if it does so before
+    // max_dist.
+    #[inline]
+    pub fn intersect(&self, origin: Vec3d, inv_dir: Vec3d, max_dist: f64) -> Option<f64> {
+        let (tx1, tx2) = ((self.min.x - origin.x) * inv_dir.x, (self.max.x - origin.x) * inv_dir.x);
+        let (ty1, ty2) = ((self.min.y - origin.y) * inv_dir.y, (self.max.y - origin.y) * inv_dir.y);
+        let (tz1, tz2) = ((self.min.z - origin.z) * inv_dir.z, (self.max.z - origin.z) * inv_dir.z);
+        let t_near = tx1.min(tx2).max(ty1.min(ty2)).max(tz1.min(tz2));
+        let t_far = tx1.max(tx2).min(ty1.max(ty2)).min(tz1.max(tz2));
+        if t_far >= t_near.max(0.0) && t_near <= max_dist { Some(t_near) } else { None }
+    }
+}
+
+#[inline]
+fn axis(v: Vec3d, axis: usize) -> f64 {
+    match axis { 0 => v.x, 1 => v.y, _ => v.z }
+}
+
+// Nodes are stored depth-first: an interior node's first child immediately follows it, and
+// `second_child` is the index of the other.
+#[derive(Debug)]
+struct Node {
+    bounds: Aabb,
+    first: usize,
+    count: usize,
+    second_child: usize
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool { self.count > 0 }
+}
+
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>
+}
+
+const NUM_BINS: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+// Relative cost of a ray/box test compared to a ray/primitive test.
+const TRAVERSAL_COST: f64 = 0.5;
+
+impl Bvh {
+    pub fn new(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh { nodes: Vec::with_capacity(2 * bounds.len()), indices: (0..bounds.len()).collect() };
+        if !bounds.is_empty() {
+            let centres: Vec<Vec3d> = bounds.iter().map(|b| b.centre()).collect();
+            bvh.build(bounds, &centres, 0, bounds.len());
+        }
+        bvh
+    }
+
+    fn build(&mut self, bounds: &[Aabb], centres: &[Vec3d], first: usize, count: usize) -> usize {
+        let node_index = self.nodes.len();
+        let node_bounds = self.indices[first..first + count].iter()
+            .fold(Aabb::empty(), |b, &i| b.union(bounds[i]));
+        self.nodes.push(Node { bounds: node_bounds, first: first, count: count, second_child: 0 });
+        if count <= 1 { return node_index; }
+
+        let centre_bounds = self.indices[first..first + count].iter()
+            .fold(Aabb::empty(), |b, &i| b.include(centres[i]));
+        let e = centre_bounds.extent();
+        let split_axis = if e.x >= e.y && e.x >= e.z { 0 } else if e.y >= e.z { 1 } else { 2 };
+        let lo = axis(centre_bounds.min, split_axis);
+        let hi = axis(centre_bounds.max, split_axis);
+        if hi <= lo {
+            // All centres coincide; nothing sensible to split on.
+            return node_index;
+        }
+
+        let bin_of = |c: Vec3d| {
+            let bin = ((axis(c, split_axis) - lo) / (hi - lo) * NUM_BINS as f64) as usize;
+            if bin >= NUM_BINS { NUM_BINS - 1 } else { bin }
+        };
+        let mut bin_bounds = [Aabb::empty(); NUM_BINS];
+        let mut bin_counts = [0usize; NUM_BINS];
+        for &i in self.indices[first..first + count].iter() {
+            let bin = bin_of(centres[i]);
+            bin_bounds[bin] = bin_bounds[bin].union(bounds[i]);
+            bin_counts[bin] += 1;
+        }
+        // Sweep from the right to find the cost of everything right of each split plane, then
+        // from the left to evaluate each candidate.
+        let mut right_area = [0.0; NUM_BINS];
+        let mut right_count = [0usize; NUM_BINS];
+        let mut acc = Aabb::empty();
+        let mut acc_count = 0;
+        for bin in (1..NUM_BINS).rev() {
+            acc = acc.union(bin_bounds[bin]);
+            acc_count += bin_counts[bin];
+            right_area[bin] = acc.surface_area();
+            right_count[bin] = acc_count;
+        }
+        let mut best_cost = f64::INFINITY;
+        let mut best_split = 0;
+        let mut acc = Aabb::empty();
+        let mut acc_count = 0;
+        for split in 1..NUM_BINS {
+            acc = acc.union(bin_bounds[split - 1]);
+            acc_count += bin_counts[split - 1];
+            if acc_count == 0 || right_count[split] == 0 { continue; }
+            let cost = acc.surface_area() * acc_count as f64 + right_area[split] * right_count[split] as f64;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+        let leaf_cost = node_bounds.surface_area() * count as f64;
+        let split_cost = TRAVERSAL_COST * node_bounds.surface_area() + best_cost;
+        if best_split == 0 || (count <= MAX_LEAF_SIZE && leaf_cost <= split_cost) {
+            return node_index;
+        }
+
+        // Partition the indices in place around the chosen plane.
+        let mut mid = first;
+        for i in first..first + count {
+            if bin_of(centres[self.indices[i]]) < best_split {
+                self.indices.swap(i, mid);
+                mid += 1;
+            }
+        }
+        self.nodes[node_index].count = 0;
+        self.build(bounds, centres, first, mid - first);
+        let second = self.build(bounds, centres, mid, first + count - mid);
+        self.nodes[node_index].second_child = second;
+        node_index
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |n| n.bounds)
+    }
+
+    // Finds the closest primitive hit by the ray, returning its index and distance.
+    // `intersect` is called with a primitive index and should return that primitive's hit
+    // distance.
+    pub fn intersect<F>(&self, ray: &Ray, mut intersect: F) -> Option<(usize, f64)>
+        where F: FnMut(usize) -> Option<f64> {
+        if self.nodes.is_empty() { return None; }
+        let inv_dir = Vec3d::new(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+        let mut closest: Option<(usize, f64)> = None;
+        let mut hit_dist = f64::INFINITY;
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.bounds.intersect(ray.origin, inv_dir, hit_dist).is_none() { continue; }
+            if node.is_leaf() {
+                for &index in self.indices[node.first..node.first + node.count].iter() {
+                    if let Some(dist) = intersect(index) {
+                        if dist < hit_dist {
+                            hit_dist = dist;
+                            closest = Some((index, dist));
+                        }
+                    }
+                }
+            } else {
+                // Visit the nearer child first so we can cull the further one more often.
+                let first_child = node_index + 1;
+                let near = self.nodes[first_child].bounds.intersect(ray.origin, inv_dir, hit_dist);
+                let far = self.nodes[node.second_child].bounds.intersect(ray.origin, inv_dir, hit_dist);
+                match (near, far) {
+                    (Some(a), Some(b)) => {
+                        if a <= b {
+                            stack.push(node.second_child);
+                            stack.push(first_child);
+                        } else {
+                            stack.push(first_child);
+                            stack.push(node.second_child);
+                        }
+                    },
+                    (Some(_), None) => stack.push(first_child),
+                    (None, Some(_)) => stack.push(node.second_child),
+                    (None, None) => {}
+                }
+            }
+        }
+        closest
+    }
+}
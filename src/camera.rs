@@ -0,0 +1,237 @@
+use error::LoadError;
+use geometry::Ray;
+use math::{AnimatedTransform, Transform, Vec3d, F64Rng};
+use sampling::{concentric_disc, regular_polygon, Distribution2D};
+
+use image;
+use std::path::Path;
+
+pub trait Camera: Send + Sync {
+    // Generates a ray through the film at (film_x, film_y). Both coordinates run from 0 to 1,
+    // with (0, 0) at the top left of the image.
+    fn generate_ray(&self, film_x: f64, film_y: f64, rng: &mut F64Rng) -> Ray;
+}
+
+// An ideal pinhole camera: everything is in perfect focus.
+#[derive(Debug, Clone, Copy)]
+pub struct PinholeCamera {
+    position: Vec3d,
+    direction: Vec3d,
+    // Film plane axes at unit distance, scaled to the full width and height of the image.
+    film_x: Vec3d,
+    film_y: Vec3d,
+    near: f64
+}
+
+impl PinholeCamera {
+    // `fov` is the vertical field of view in degrees, and `aspect` the image's width / height.
+    // Panics if `up` is parallel to `direction`.
+    pub fn new(position: Vec3d, direction: Vec3d, up: Vec3d, fov: f64, aspect: f64) -> PinholeCamera {
+        let direction = direction.normalized();
+        let right = direction.cross(up);
+        assert!(right.length_squared() > 1e-12, "Camera up vector is parallel to its direction");
+        let right = right.normalized();
+        let film_height = 2.0 * (fov.to_radians() / 2.0).tan();
+        PinholeCamera {
+            position: position,
+            direction: direction,
+            film_x: right * (film_height * aspect),
+            film_y: right.cross(direction) * film_height,
+            near: 0.0
+        }
+    }
+
+    pub fn look_at(position: Vec3d, target: Vec3d, up: Vec3d, fov: f64, aspect: f64) -> PinholeCamera {
+        PinholeCamera::new(position, target - position, up, fov, aspect)
+    }
+
+    // Starts rays `near` units in front of the camera, so it can sit outside a closed scene
+    // (like the smallpt Cornell box) and still see in.
+    pub fn with_near(self, near: f64) -> PinholeCamera {
+        PinholeCamera { near: near, ..self }
+    }
+
+    // The normalized direction through a point on the film.
+    fn direction_through(&self, film_x: f64, film_y: f64) -> Vec3d {
+        (self.direction + self.film_x * (film_x - 0.5) + self.film_y * (0.5 - film_y)).normalized()
+    }
+}
+
+impl Camera for PinholeCamera {
+    fn generate_ray(&self, film_x: f64, film_y: f64, _rng: &mut F64Rng) -> Ray {
+        let dir = self.direction_through(film_x, film_y);
+        Ray::new(self.position + dir * self.near, dir)
+    }
+}
+
+// The shape of a lens aperture, which determines the shape of out-of-focus highlights.
+#[derive(Debug, Clone)]
+pub enum Aperture {
+    Circle,
+    // A regular polygon as made by a diaphragm with straight blades. Rotation is in radians.
+    Polygon { blades: u32, rotation: f64 },
+    // An arbitrary mask: light passes through each point in proportion to its brightness.
+    Image(Distribution2D)
+}
+
+impl Aperture {
+    // Loads a greyscale mask from an image. The image is stretched to cover the lens.
+    pub fn from_image<P: AsRef<Path>>(path: P) -> Result<Aperture, LoadError> {
+        let path = path.as_ref();
+        let invalid = |message: String| LoadError::InvalidError { file: path.display().to_string(), message: message };
+        let mask = image::open(path).map_err(|e| invalid(e.to_string()))?.to_luma();
+        let (width, height) = (mask.width() as usize, mask.height() as usize);
+        let weights: Vec<f64> = mask.pixels().map(|p| p.data[0] as f64).collect();
+        if !weights.iter().any(|&w| w > 0.0) {
+            return Err(invalid("Aperture image is completely black".to_string()));
+        }
+        Ok(Aperture::Image(Distribution2D::new(&weights, width, height)))
+    }
+
+    // Picks a point on the aperture, scaled to fit the unit circle (or square, for images).
+    fn sample(&self, rng: &mut F64Rng) -> (f64, f64) {
+        match *self {
+            Aperture::Circle => concentric_disc(rng.next(), rng.next()),
+            Aperture::Polygon { blades, rotation } =>
+                regular_polygon(blades, rotation, rng.next(), rng.next(), rng.next()),
+            Aperture::Image(ref distribution) => {
+                let ((x, y), _) = distribution.sample_continuous(rng.next(), rng.next());
+                // Image rows run top to bottom.
+                (2.0 * x - 1.0, 1.0 - 2.0 * y)
+            }
+        }
+    }
+}
+
+// A camera with a thin lens, giving depth of field. Points `focus_distance` away (measured
+// along the view direction) are in perfect focus; everything else is blurred according to the
+// size and shape of the aperture.
+#[derive(Debug, Clone)]
+pub struct ThinLensCamera {
+    pinhole: PinholeCamera,
+    lens_radius: f64,
+    focus_distance: f64,
+    aperture: Aperture
+}
+
+impl ThinLensCamera {
+    pub fn new(pinhole: PinholeCamera, lens_radius: f64, focus_distance: f64, aperture: Aperture) -> ThinLensCamera {
+        ThinLensCamera {
+            pinhole: pinhole,
+            lens_radius: lens_radius,
+            focus_distance: focus_distance,
+            aperture: aperture
+        }
+    }
+}
+
+impl Camera for ThinLensCamera {
+    fn generate_ray(&self, film_x: f64, film_y: f64, rng: &mut F64Rng) -> Ray {
+        let camera = &self.pinhole;
+        let dir = camera.direction_through(film_x, film_y);
+        let focus_point = camera.position + dir * (self.focus_distance / dir.dot(camera.direction));
+        let (lens_x, lens_y) = self.aperture.sample(rng);
+        let lens_point = camera.position
+            + camera.film_x.normalized() * (lens_x * self.lens_radius)
+            + camera.film_y.normalized() * (lens_y * self.lens_radius);
+        let dir = (focus_point - lens_point).normalized();
+        Ray::new(lens_point + dir * camera.near, dir)
+    }
+}
+
+// Wraps another camera to give its rays times within the part of the frame the shutter is open
+// for, so that anything moving blurs. The camera itself can move too, between poses that take
+// its own space (looking down -z, with y up) to the world. The wrapped camera should be placed
+// at the first of them.
+pub struct ShutterCamera {
+    camera: Box<Camera>,
+    open: f64,
+    close: f64,
+    poses: Option<(AnimatedTransform, Transform)>
+}
+
+impl ShutterCamera {
+    pub fn new(camera: Box<Camera>, open: f64, close: f64) -> ShutterCamera {
+        ShutterCamera { camera: camera, open: open, close: close, poses: None }
+    }
+
+    pub fn with_poses(self, poses: AnimatedTransform) -> ShutterCamera {
+        let placed = poses.at(poses.keyframes()[0].time).inverse();
+        ShutterCamera { poses: Some((poses, placed)), ..self }
+    }
+}
+
+impl Camera for ShutterCamera {
+    fn generate_ray(&self, film_x: f64, film_y: f64, rng: &mut F64Rng) -> Ray {
+        let time = self.open + (self.close - self.open) * rng.next();
+        let ray = self.camera.generate_ray(film_x, film_y, rng);
+        match self.poses {
+            Some((ref poses, placed)) => {
+                let moved = poses.at(time) * placed;
+                Ray::new(moved.point(ray.origin), moved.vector(ray.direction).normalized()).with_time(time)
+            },
+            None => ray.with_time(time)
+        }
+    }
+}
+
+#[test]
+fn pinhole_camera() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let camera = PinholeCamera::look_at(Vec3d::new(0.0, 0.0, 10.0), Vec3d::zero(),
+                                        Vec3d::new(0.0, 1.0, 0.0), 90.0, 2.0);
+    let centre = camera.generate_ray(0.5, 0.5, &mut rng);
+    assert_eq!(centre.direction.z, -1.0);
+    // With a 90 degree vertical fov the top edge of the film is at 45 degrees.
+    let top = camera.generate_ray(0.5, 0.0, &mut rng);
+    assert!((top.direction.y - top.direction.z.abs()).abs() < 1e-9);
+    assert!(top.direction.y > 0.0);
+    // ...and the image is twice as wide as it is high.
+    let right = camera.generate_ray(1.0, 0.5, &mut rng);
+    assert!((right.direction.x / right.direction.z.abs() - 2.0).abs() < 1e-9);
+}
+
+#[test]
+fn thin_lens_focuses() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let pinhole = PinholeCamera::look_at(Vec3d::zero(), Vec3d::new(0.0, 0.0, -1.0),
+                                         Vec3d::new(0.0, 1.0, 0.0), 40.0, 1.5);
+    let apertures = [Aperture::Circle, Aperture::Polygon { blades: 6, rotation: 0.3 }];
+    for aperture in apertures.iter() {
+        let camera = ThinLensCamera::new(pinhole, 0.5, 10.0, aperture.clone());
+        for _ in 0..100 {
+            // Every ray through the same film point should meet at the focal plane.
+            let ray = camera.generate_ray(0.2, 0.7, &mut rng);
+            assert!(ray.origin.length() <= 0.5 + 1e-9);
+            let expected = pinhole.generate_ray(0.2, 0.7, &mut rng).direction;
+            let focus = ray.origin + ray.direction * ((-10.0 - ray.origin.z) / ray.direction.z);
+            let on_axis = expected * (10.0 / -expected.z);
+            assert!((focus - on_axis).length() < 1e-9);
+        }
+    }
+}
+
+#[test]
+fn shutter_camera_moves() {
+    use math::{Keyframe, Quaternion};
+    use rand::{SeedableRng, XorShiftRng};
+    use std::f64::consts::PI;
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let pinhole = PinholeCamera::look_at(Vec3d::new(0.0, 0.0, 5.0), Vec3d::zero(), Vec3d::new(0.0, 1.0, 0.0), 40.0, 1.0);
+    // Slides along x while turning to look down +x.
+    let mut start = Keyframe::new(0.0);
+    start.translation = Vec3d::new(0.0, 0.0, 5.0);
+    let mut end = Keyframe::new(1.0);
+    end.translation = Vec3d::new(2.0, 0.0, 5.0);
+    end.rotation = Quaternion::from_axis_angle(Vec3d::new(0.0, 1.0, 0.0), -PI / 2.0);
+    let camera = ShutterCamera::new(Box::new(pinhole), 0.25, 0.75).with_poses(AnimatedTransform::new(vec![start, end]));
+    for _ in 0..100 {
+        let ray = camera.generate_ray(0.5, 0.5, &mut rng);
+        assert!(ray.time >= 0.25 && ray.time <= 0.75);
+        assert!((ray.origin - Vec3d::new(2.0 * ray.time, 0.0, 5.0)).length() < 1e-9);
+        let angle = PI / 2.0 * ray.time;
+        assert!((ray.direction - Vec3d::new(angle.sin(), 0.0, -angle.cos())).length() < 1e-9);
+    }
+}
@@ -0,0 +1,31 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Errors from loading any of our text-based input files. Parse errors always name the file and
+// the (1-based) line they occurred on; invalid files are syntactically fine but incomplete.
+#[derive(Debug)]
+pub enum LoadError {
+    IoError(String, io::Error),
+    ParseError { file: String, line: usize, message: String },
+    InvalidError { file: String, message: String }
+}
+
+impl LoadError {
+    pub fn parse<S: Into<String>>(file: &str, line: usize, message: S) -> LoadError {
+        LoadError::ParseError { file: file.to_string(), line: line, message: message.into() }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::IoError(ref file, ref e) => write!(f, "{}: {}", file, e),
+            LoadError::ParseError { ref file, line, ref message } =>
+                write!(f, "{}:{}: {}", file, line, message),
+            LoadError::InvalidError { ref file, ref message } => write!(f, "{}: {}", file, message)
+        }
+    }
+}
+
+impl Error for LoadError {}
@@ -1,26 +1,39 @@
+use bvh::Aabb;
 use material::Material;
 use renderable::{Hit, Renderable};
-use math::{Vec3d, F64Rng};
+use math::{Onb, Vec3d, F64Rng};
+use sampling::concentric_disc;
+use texture::{constant_texture, NormalMap, Texture};
 use std::f64::consts::PI;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3d,
-    pub direction: Vec3d
+    pub direction: Vec3d,
+    // When the ray was sent, for motion blur. Runs from 0 to 1 over the frame.
+    pub time: f64
 }
 
 impl Ray {
     pub fn new(origin: Vec3d, direction: Vec3d) -> Ray {
-        Ray { origin: origin, direction: direction }
+        Ray { origin: origin, direction: direction, time: 0.0 }
+    }
+    pub fn with_time(self, time: f64) -> Ray {
+        Ray { time: time, ..self }
     }
 }
 
 pub struct Sphere {
     material: Material,
+    radius: f64,
     radius_squared: f64,
     position: Vec3d,
+    // How far the centre moves over the frame.
+    motion: Vec3d,
     emission: Vec3d,
-    colour: Vec3d,
+    colour: Arc<Texture>,
+    normal_map: Option<NormalMap>,
     emissive: bool,
 }
 
@@ -32,29 +45,68 @@ impl Sphere {
     pub fn new(material: Material, radius: f64, position: Vec3d, emission: Vec3d, colour: Vec3d) -> Sphere {
         Sphere {
             material: material,
+            radius: radius,
             radius_squared: radius * radius,
             position: position,
+            motion: Vec3d::zero(),
             emission: emission,
-            colour: colour,
+            colour: constant_texture(colour),
+            normal_map: None,
             emissive: emission.max_component() > 0.0
         }
     }
+    pub fn with_texture(self, texture: Arc<Texture>) -> Sphere {
+        Sphere { colour: texture, ..self }
+    }
+    pub fn with_normal_map(self, map: NormalMap) -> Sphere {
+        Sphere { normal_map: Some(map), ..self }
+    }
+    // Moves the centre in a straight line, from its position at time 0 to `end` at time 1.
+    pub fn with_motion(self, end: Vec3d) -> Sphere {
+        Sphere { motion: end - self.position, ..self }
+    }
+    fn centre(&self, time: f64) -> Vec3d {
+        self.position + self.motion * time
+    }
 }
 
 impl Renderable for Sphere {
     fn get_hit(&self, ray: &Ray, dist: f64) -> Hit {
         let pos = ray.origin + ray.direction * dist;
-        let normal = (pos - self.position).normalized();
+        let normal = (pos - self.centre(ray.time)).normalized();
+        // Longitude and latitude, with the seam facing +z.
+        let uv = (0.5 + normal.x.atan2(-normal.z) / (2.0 * PI), 1.0 - normal.y.clamp(-1.0, 1.0).acos() / PI);
+        // The derivatives of (r sin(theta) sin(phi), r cos(theta), -r sin(theta) cos(phi)), with
+        // phi = 2 pi (u - 1/2) and theta = pi (1 - v). Longitude is undefined at the poles.
+        let sin_theta = (normal.x * normal.x + normal.z * normal.z).sqrt();
+        let (dpdu, dpdv) = if sin_theta > 0.0 {
+            (Vec3d::new(-normal.z, 0.0, normal.x) * (2.0 * PI * self.radius),
+             Vec3d::new(normal.y * normal.x / sin_theta, -sin_theta, normal.y * normal.z / sin_theta)
+                 * (-PI * self.radius))
+        } else {
+            let frame = Onb::from_w(normal);
+            (frame.u, frame.v)
+        };
         Hit {
             pos: pos,
             normal: normal,
-            material: &self.material,
-            colour: self.colour,
-            emission: self.emission
-        }
+            geometric_normal: normal,
+            material: &*self.material,
+            colour: self.colour.value(uv, pos),
+            emission: self.emission,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            object: self
+        }.with_normal_map(self.normal_map.as_ref())
+    }
+    fn bounding_box(&self) -> Aabb {
+        let r = Vec3d::new(self.radius, self.radius, self.radius);
+        let end = self.centre(1.0);
+        Aabb::new(self.position.min(end) - r, self.position.max(end) + r)
     }
     fn intersect(&self, ray: &Ray) -> Option<f64> {
-        let op = self.position - ray.origin;
+        let op = self.centre(ray.time) - ray.origin;
         let b = op.dot(ray.direction);
         let determinant = b * b - op.dot(op) + self.radius_squared;
         if determinant < 0.0 { return None; }
@@ -71,17 +123,12 @@ impl Renderable for Sphere {
         }
     }
     fn is_emissive(&self) -> bool { self.emissive }
-    fn random_emission(&self, from: Vec3d, normal: Vec3d, rng: &mut F64Rng) -> (Vec3d, Vec3d) {
-        let pos_to_center = self.position - from;
+    fn random_emission(&self, from: Vec3d, time: f64, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64) {
+        let pos_to_center = self.centre(time) - from;
         let dist_squared = pos_to_center.length_squared();
-        let sw = pos_to_center.normalized();
-        // todo make an ONB func
-        let su = if sw.x.abs() > 0.1 {
-            Vec3d::new(0.0, 1.0, 0.0)
-        } else {
-            Vec3d::new(1.0, 0.0, 0.0)
-        }.cross(sw).normalized();
-        let sv = sw.cross(su);
+        // From inside, there's no cone of directions to pick from.
+        if dist_squared <= self.radius_squared { return (Vec3d::zero(), Vec3d::zero(), 0.0); }
+        let frame = Onb::from_w(pos_to_center.normalized());
         // radius / dist = opp / adjacent = sin(angle), we need cos(angle)
         // sin^2(a)+cos^2(a) = 1, so cos(a) = sqrt(1-sin^2(a)) = sqrt(1-opp^2/adj^2).
         let cos_a_max = (1.0 - self.radius_squared / dist_squared).sqrt();
@@ -90,10 +137,242 @@ impl Renderable for Sphere {
         let cos_a = 1.0 - eps1 + eps1 * cos_a_max;
         let sin_a = (1.0 - cos_a * cos_a).sqrt();
         let phi = 2.0 * PI * eps2;
-        let l = (su * phi.cos() * sin_a + sv * phi.sin() * sin_a + sw * cos_a).normalized();
+        let l = frame.to_world(Vec3d::new(phi.cos() * sin_a, phi.sin() * sin_a, cos_a)).normalized();
         let omega = 2.0 * PI * (1.0 - cos_a_max);
-        let emission = self.emission * l.dot(normal) * omega * (1.0 / PI);
-        (l, emission)
+        (l, self.emission, 1.0 / omega)
+    }
+    fn emission_pdf(&self, from: Vec3d, direction: Vec3d, time: f64) -> f64 {
+        let pos_to_center = self.centre(time) - from;
+        let dist_squared = pos_to_center.length_squared();
+        if dist_squared <= self.radius_squared { return 0.0; }
+        let cos_a_max = (1.0 - self.radius_squared / dist_squared).sqrt();
+        if direction.dot(pos_to_center.normalized()) < cos_a_max { return 0.0; }
+        1.0 / (2.0 * PI * (1.0 - cos_a_max))
+    }
+    fn power(&self) -> f64 {
+        // A Lambertian emitter radiates pi times its radiance from each unit of area.
+        4.0 * PI * self.radius_squared * PI * self.emission.luminance()
+    }
+    fn identity(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+// Shared by the flat emitters below: where a ray meets the plane through `point` with normal
+// `normal`, if it does so in front of the ray.
+fn intersect_plane(ray: &Ray, point: Vec3d, normal: Vec3d) -> Option<f64> {
+    let denom = normal.dot(ray.direction);
+    if denom.abs() < 1e-12 { return None; }
+    let t = normal.dot(point - ray.origin) / denom;
+    const EPSILON: f64 = 0.0001;
+    if t > EPSILON { Some(t) } else { None }
+}
+
+// Converts a uniformly chosen point on a flat emitter into what Renderable::random_emission
+// expects. One-sided emitters only light what's in front of them.
+fn planar_emission(point: Vec3d, normal: Vec3d, area: f64, two_sided: bool, emission: Vec3d,
+                   from: Vec3d) -> (Vec3d, Vec3d, f64) {
+    let to_light = point - from;
+    let dist_squared = to_light.length_squared();
+    let l = to_light.normalized();
+    let cos_light = -normal.dot(l);
+    let cos_light = if two_sided { cos_light.abs() } else { cos_light };
+    if dist_squared <= 0.0 || cos_light <= 0.0 { return (l, Vec3d::zero(), 0.0); }
+    (l, emission, dist_squared / (area * cos_light))
+}
+
+// The solid angle pdf of planar_emission having picked the point `dist` along `direction`.
+fn planar_emission_pdf(dist: f64, direction: Vec3d, normal: Vec3d, area: f64, two_sided: bool) -> f64 {
+    let cos_light = -normal.dot(direction);
+    let cos_light = if two_sided { cos_light.abs() } else { cos_light };
+    if cos_light <= 0.0 { 0.0 } else { dist * dist / (area * cos_light) }
+}
+
+// A parallelogram with one corner at `corner` and edges `u` and `v`; a rectangle if they're
+// perpendicular. It faces along u x v, and only emits that way unless it's two-sided.
+pub struct Quad {
+    material: Material,
+    corner: Vec3d,
+    u: Vec3d,
+    v: Vec3d,
+    normal: Vec3d,
+    area: f64,
+    emission: Vec3d,
+    colour: Arc<Texture>,
+    normal_map: Option<NormalMap>,
+    two_sided: bool
+}
+
+impl Quad {
+    pub fn new(material: Material, corner: Vec3d, u: Vec3d, v: Vec3d, emission: Vec3d, colour: Vec3d) -> Quad {
+        let cross = u.cross(v);
+        Quad {
+            material: material,
+            corner: corner,
+            u: u,
+            v: v,
+            normal: cross.normalized(),
+            area: cross.length(),
+            emission: emission,
+            colour: constant_texture(colour),
+            normal_map: None,
+            two_sided: false
+        }
+    }
+    pub fn two_sided(self) -> Quad {
+        Quad { two_sided: true, ..self }
+    }
+    pub fn with_texture(self, texture: Arc<Texture>) -> Quad {
+        Quad { colour: texture, ..self }
+    }
+    pub fn with_normal_map(self, map: NormalMap) -> Quad {
+        Quad { normal_map: Some(map), ..self }
+    }
+    // Coordinates of a point in the quad's plane along each edge, by projecting onto the
+    // reciprocal basis.
+    fn coordinates(&self, point: Vec3d) -> (f64, f64) {
+        let rel = point - self.corner;
+        let w = self.normal * (1.0 / self.area);
+        (w.dot(rel.cross(self.v)), w.dot(self.u.cross(rel)))
+    }
+}
+
+impl Renderable for Quad {
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        intersect_plane(ray, self.corner, self.normal).and_then(|t| {
+            let (a, b) = self.coordinates(ray.origin + ray.direction * t);
+            if (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b) { Some(t) } else { None }
+        })
+    }
+    fn get_hit(&self, ray: &Ray, dist: f64) -> Hit<'_> {
+        let front = ray.direction.dot(self.normal) < 0.0;
+        let pos = ray.origin + ray.direction * dist;
+        let uv = self.coordinates(pos);
+        Hit {
+            pos: pos,
+            normal: self.normal,
+            geometric_normal: self.normal,
+            material: &*self.material,
+            colour: self.colour.value(uv, pos),
+            emission: if front || self.two_sided { self.emission } else { Vec3d::zero() },
+            uv: uv,
+            dpdu: self.u,
+            dpdv: self.v,
+            object: self
+        }.with_normal_map(self.normal_map.as_ref())
+    }
+    fn bounding_box(&self) -> Aabb {
+        let c = self.corner;
+        Aabb::from_points(&[c, c + self.u, c + self.v, c + self.u + self.v])
+    }
+    fn is_emissive(&self) -> bool { self.emission.max_component() > 0.0 }
+    fn random_emission(&self, from: Vec3d, _time: f64, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64) {
+        let point = self.corner + self.u * rng.next() + self.v * rng.next();
+        planar_emission(point, self.normal, self.area, self.two_sided, self.emission, from)
+    }
+    fn emission_pdf(&self, from: Vec3d, direction: Vec3d, _time: f64) -> f64 {
+        self.intersect(&Ray::new(from, direction))
+            .map_or(0.0, |dist| planar_emission_pdf(dist, direction, self.normal, self.area, self.two_sided))
+    }
+    fn power(&self) -> f64 {
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        sides * self.area * PI * self.emission.luminance()
+    }
+    fn identity(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+// A flat disc, facing along `normal`. Like Quad it's one-sided unless told otherwise.
+pub struct Disc {
+    material: Material,
+    centre: Vec3d,
+    normal: Vec3d,
+    radius: f64,
+    emission: Vec3d,
+    colour: Arc<Texture>,
+    normal_map: Option<NormalMap>,
+    two_sided: bool
+}
+
+impl Disc {
+    pub fn new(material: Material, centre: Vec3d, normal: Vec3d, radius: f64, emission: Vec3d, colour: Vec3d) -> Disc {
+        Disc {
+            material: material,
+            centre: centre,
+            normal: normal.normalized(),
+            radius: radius,
+            emission: emission,
+            colour: constant_texture(colour),
+            normal_map: None,
+            two_sided: false
+        }
+    }
+    pub fn two_sided(self) -> Disc {
+        Disc { two_sided: true, ..self }
+    }
+    pub fn with_texture(self, texture: Arc<Texture>) -> Disc {
+        Disc { colour: texture, ..self }
+    }
+    pub fn with_normal_map(self, map: NormalMap) -> Disc {
+        Disc { normal_map: Some(map), ..self }
+    }
+    fn area(&self) -> f64 {
+        PI * self.radius * self.radius
+    }
+}
+
+impl Renderable for Disc {
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        intersect_plane(ray, self.centre, self.normal).and_then(|t| {
+            let rel = ray.origin + ray.direction * t - self.centre;
+            if rel.length_squared() <= self.radius * self.radius { Some(t) } else { None }
+        })
+    }
+    fn get_hit(&self, ray: &Ray, dist: f64) -> Hit<'_> {
+        let front = ray.direction.dot(self.normal) < 0.0;
+        let pos = ray.origin + ray.direction * dist;
+        // The angle around the centre, and the distance from it.
+        let frame = Onb::from_w(self.normal);
+        let rel = pos - self.centre;
+        let angle = rel.dot(frame.v).atan2(rel.dot(frame.u));
+        let uv = (0.5 + angle / (2.0 * PI), rel.length() / self.radius);
+        // Radial, and around the rim.
+        let radial = frame.u * angle.cos() + frame.v * angle.sin();
+        Hit {
+            pos: pos,
+            normal: self.normal,
+            geometric_normal: self.normal,
+            material: &*self.material,
+            colour: self.colour.value(uv, pos),
+            emission: if front || self.two_sided { self.emission } else { Vec3d::zero() },
+            uv: uv,
+            dpdu: self.normal.cross(radial) * (2.0 * PI * rel.length()),
+            dpdv: radial * self.radius,
+            object: self
+        }.with_normal_map(self.normal_map.as_ref())
+    }
+    fn bounding_box(&self) -> Aabb {
+        // How far the rim reaches along each axis.
+        let n = self.normal;
+        let extent = Vec3d::new((1.0 - n.x * n.x).max(0.0).sqrt(), (1.0 - n.y * n.y).max(0.0).sqrt(),
+                                (1.0 - n.z * n.z).max(0.0).sqrt()) * self.radius;
+        Aabb::new(self.centre - extent, self.centre + extent)
+    }
+    fn is_emissive(&self) -> bool { self.emission.max_component() > 0.0 }
+    fn random_emission(&self, from: Vec3d, _time: f64, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64) {
+        let frame = Onb::from_w(self.normal);
+        let (x, y) = concentric_disc(rng.next(), rng.next());
+        let point = self.centre + frame.to_world(Vec3d::new(x, y, 0.0)) * self.radius;
+        planar_emission(point, self.normal, self.area(), self.two_sided, self.emission, from)
+    }
+    fn emission_pdf(&self, from: Vec3d, direction: Vec3d, _time: f64) -> f64 {
+        self.intersect(&Ray::new(from, direction))
+            .map_or(0.0, |dist| planar_emission_pdf(dist, direction, self.normal, self.area(), self.two_sided))
+    }
+    fn power(&self) -> f64 {
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        sides * self.area() * PI * self.emission.luminance()
     }
     fn identity(&self) -> u64 {
         self as *const Self as u64
@@ -102,8 +381,10 @@ impl Renderable for Sphere {
 
 #[test]
 fn intersection() {
+    use material::Lambertian;
+    use std::sync::Arc;
     let sphere = Sphere::new(
-        Material::Diffuse,
+        Arc::new(Lambertian),
         100.0,
         Vec3d::new(0.0, 0.0, 200.0),
         Vec3d::zero(),
@@ -119,3 +400,79 @@ fn intersection() {
         None => {}
     }
 }
+
+#[test]
+fn emission_pdf_matches_sampling() {
+    use material::Lambertian;
+    use rand::{SeedableRng, XorShiftRng};
+    use std::sync::Arc;
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let light = Sphere::new(Arc::new(Lambertian), 2.0, Vec3d::new(0.0, 10.0, 0.0), Vec3d::one(), Vec3d::zero());
+    let from = Vec3d::new(1.0, 0.0, 0.0);
+    for _ in 0..100 {
+        let (direction, emission, pdf) = light.random_emission(from, 0.0, &mut rng);
+        assert_eq!(emission.x, 1.0);
+        assert!(light.intersect(&Ray::new(from, direction)).is_some());
+        assert!((light.emission_pdf(from, direction, 0.0) - pdf).abs() < 1e-9);
+    }
+    assert_eq!(light.emission_pdf(from, Vec3d::new(0.0, -1.0, 0.0), 0.0), 0.0);
+    // There's nothing to sample from inside.
+    assert_eq!(light.random_emission(Vec3d::new(0.0, 10.5, 0.0), 0.0, &mut rng).2, 0.0);
+}
+
+#[test]
+fn flat_emitters() {
+    use material::Lambertian;
+    use rand::{SeedableRng, XorShiftRng};
+    use std::sync::Arc;
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let down = Vec3d::new(0.0, -1.0, 0.0);
+    let quad = Quad::new(Arc::new(Lambertian), Vec3d::new(-1.0, 5.0, -1.0), Vec3d::new(2.0, 0.0, 0.0),
+                         Vec3d::new(0.0, 0.0, 2.0), Vec3d::one(), Vec3d::zero());
+    let disc = Disc::new(Arc::new(Lambertian), Vec3d::new(0.0, 5.0, 0.0), down, 1.0, Vec3d::one(), Vec3d::zero());
+    let emitters: [&Renderable; 2] = [&quad, &disc];
+    for emitter in emitters.iter() {
+        let from = Vec3d::new(0.3, 0.0, 0.2);
+        for _ in 0..100 {
+            let (direction, _, pdf) = emitter.random_emission(from, 0.0, &mut rng);
+            assert!(pdf > 0.0);
+            assert!((emitter.emission_pdf(from, direction, 0.0) - pdf).abs() < 1e-9 * pdf);
+        }
+        // Straight up from below, the pdf is just distance^2 / area.
+        let area = if emitter.identity() == quad.identity() { 4.0 } else { PI };
+        let pdf = emitter.emission_pdf(Vec3d::zero(), down.neg(), 0.0);
+        assert!((pdf - 25.0 / area).abs() < 1e-9);
+        // Neither emits upwards.
+        assert_eq!(emitter.random_emission(Vec3d::new(0.0, 10.0, 0.0), 0.0, &mut rng).2, 0.0);
+        assert_eq!(emitter.get_hit(&Ray::new(Vec3d::new(0.0, 10.0, 0.0), down), 5.0).emission.x, 0.0);
+    }
+}
+
+#[test]
+fn surface_derivatives() {
+    use material::Lambertian;
+    use std::sync::Arc;
+    let sphere = Sphere::new(Arc::new(Lambertian), 2.0, Vec3d::new(1.0, 2.0, 3.0), Vec3d::zero(), Vec3d::one());
+    let disc = Disc::new(Arc::new(Lambertian), Vec3d::zero(), Vec3d::new(0.0, 1.0, 0.0), 2.0, Vec3d::zero(), Vec3d::one());
+    // Looking at each point from where it's hit, stepping along dpdu and dpdv should move the
+    // surface coordinates by the same step in u or v.
+    let views = [(&sphere as &Renderable, sphere.position, Vec3d::new(0.3, -0.4, 0.5).normalized()),
+                 (&disc as &Renderable, Vec3d::new(0.7, 3.0, -0.2), Vec3d::new(0.1, -1.0, 0.3).normalized())];
+    for &(object, origin, direction) in views.iter() {
+        let hit_at = |target: Vec3d| {
+            let ray = Ray::new(origin, (target - origin).normalized());
+            object.get_hit(&ray, object.intersect(&ray).unwrap())
+        };
+        let hit = hit_at(origin + direction);
+        let step = 1e-4;
+        let du = hit_at(hit.pos + hit.dpdu * step).uv;
+        let dv = hit_at(hit.pos + hit.dpdv * step).uv;
+        assert!((du.0 - hit.uv.0 - step).abs() < 1e-6 && (du.1 - hit.uv.1).abs() < 1e-6);
+        assert!((dv.0 - hit.uv.0).abs() < 1e-6 && (dv.1 - hit.uv.1 - step).abs() < 1e-6);
+        // The shading frame is orthonormal, right handed and lined up with dpdu.
+        let frame = hit.shading_frame();
+        assert!((frame.u.cross(frame.v) - frame.w).length() < 1e-9);
+        assert!((frame.u.dot(hit.dpdu) - hit.dpdu.length()).abs() < 1e-9);
+        assert!((frame.to_world(frame.to_local(direction)) - direction).length() < 1e-9);
+    }
+}
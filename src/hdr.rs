@@ -0,0 +1,430 @@
+// Floating point image input and output, keeping the full dynamic range of a render for
+// compositing, or of an environment map for lighting. Images are rows of linear RGB, top row
+// first.
+use error::LoadError;
+use math::Vec3d;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExrPixelType {
+    Half,
+    Float
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HdrFormat {
+    Pfm,
+    Radiance,
+    Exr(ExrPixelType)
+}
+
+impl HdrFormat {
+    // Picks a format from a filename's extension; None means it's not a floating point format.
+    pub fn from_filename(filename: &str, exr_pixel_type: ExrPixelType) -> Option<HdrFormat> {
+        let extension = Path::new(filename).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("pfm") => Some(HdrFormat::Pfm),
+            Some("hdr") | Some("pic") => Some(HdrFormat::Radiance),
+            Some("exr") => Some(HdrFormat::Exr(exr_pixel_type)),
+            _ => None
+        }
+    }
+}
+
+pub fn write_hdr_image<W: Write>(writer: &mut W, format: HdrFormat, rows: &[Vec<Vec3d>]) -> io::Result<()> {
+    match format {
+        HdrFormat::Pfm => write_pfm(writer, rows),
+        HdrFormat::Radiance => write_radiance(writer, rows),
+        HdrFormat::Exr(pixel_type) => write_exr(writer, rows, pixel_type)
+    }
+}
+
+fn dimensions(rows: &[Vec<Vec3d>]) -> (usize, usize) {
+    (rows.first().map_or(0, |row| row.len()), rows.len())
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    write_u32(writer, value as u32)?;
+    write_u32(writer, (value >> 32) as u32)
+}
+
+fn write_f32<W: Write>(writer: &mut W, value: f32) -> io::Result<()> {
+    write_u32(writer, value.to_bits())
+}
+
+// Portable float map: a tiny header, then little-endian floats with the bottom row first.
+pub fn write_pfm<W: Write>(writer: &mut W, rows: &[Vec<Vec3d>]) -> io::Result<()> {
+    let (width, height) = dimensions(rows);
+    // A negative scale marks the data as little-endian.
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in rows.iter().rev() {
+        for pixel in row.iter() {
+            write_f32(writer, pixel.x as f32)?;
+            write_f32(writer, pixel.y as f32)?;
+            write_f32(writer, pixel.z as f32)?;
+        }
+    }
+    Ok(())
+}
+
+// Converts to Radiance's shared-exponent RGBE encoding.
+fn to_rgbe(pixel: Vec3d) -> [u8; 4] {
+    let max = pixel.max_component();
+    if !max.is_finite() || max <= 1e-32 { return [0, 0, 0, 0]; }
+    // max = mantissa * 2^exponent, with mantissa in [0.5, 1).
+    let exponent = max.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2f64.powi(exponent);
+    let channel = |v: f64| (v.max(0.0) * scale).min(255.0) as u8;
+    [channel(pixel.x), channel(pixel.y), channel(pixel.z), (exponent + 128).clamp(0, 255) as u8]
+}
+
+// Radiance RGBE. Scanlines are written flat rather than run-length encoded, which every reader
+// accepts.
+pub fn write_radiance<W: Write>(writer: &mut W, rows: &[Vec<Vec3d>]) -> io::Result<()> {
+    let (width, height) = dimensions(rows);
+    write!(writer, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+    for row in rows.iter() {
+        for &pixel in row.iter() {
+            writer.write_all(&to_rgbe(pixel))?;
+        }
+    }
+    Ok(())
+}
+
+// Converts to an IEEE 754 half float, rounding to nearest even. Values too large for a half
+// become infinity.
+pub fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity stays infinity; NaN keeps a set mantissa bit.
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if half_exponent <= 0 {
+        // Denormal (or zero) in half precision.
+        if half_exponent < -10 { return sign; }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - half_exponent) as u32;
+        let half_mantissa = mantissa >> shift;
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round_up = remainder > halfway || (remainder == halfway && half_mantissa & 1 != 0);
+        return sign | (half_mantissa + if round_up { 1 } else { 0 }) as u16;
+    }
+    let half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+    let remainder = mantissa & 0x1fff;
+    let round_up = remainder > 0x1000 || (remainder == 0x1000 && half & 1 != 0);
+    // Rounding may carry into the exponent, which correctly gives the next power of two (or
+    // infinity).
+    sign | (half + if round_up { 1 } else { 0 }) as u16
+}
+
+fn write_attribute<W: Write>(writer: &mut W, name: &str, kind: &str, value: &[u8]) -> io::Result<()> {
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&[0])?;
+    writer.write_all(kind.as_bytes())?;
+    writer.write_all(&[0])?;
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value)
+}
+
+// Uncompressed scanline OpenEXR, with B, G and R channels (EXR wants them sorted by name).
+pub fn write_exr<W: Write>(writer: &mut W, rows: &[Vec<Vec3d>], pixel_type: ExrPixelType) -> io::Result<()> {
+    let (width, height) = dimensions(rows);
+    if width == 0 || height == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Can't write an empty image as EXR"));
+    }
+    let (type_code, bytes_per_value) = match pixel_type {
+        ExrPixelType::Half => (1u32, 2),
+        ExrPixelType::Float => (2u32, 4)
+    };
+    let mut header: Vec<u8> = Vec::new();
+    // Magic number, then version 2 with no flags (single part, scanlines).
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+    let mut channels: Vec<u8> = Vec::new();
+    for name in ["B", "G", "R"].iter() {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        write_u32(&mut channels, type_code)?;
+        // pLinear and three reserved bytes, then x and y sampling.
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        write_u32(&mut channels, 1)?;
+        write_u32(&mut channels, 1)?;
+    }
+    channels.push(0);
+    write_attribute(&mut header, "channels", "chlist", &channels)?;
+    write_attribute(&mut header, "compression", "compression", &[0])?;
+    let mut window: Vec<u8> = Vec::new();
+    for &v in [0, 0, width as u32 - 1, height as u32 - 1].iter() {
+        write_u32(&mut window, v)?;
+    }
+    write_attribute(&mut header, "dataWindow", "box2i", &window)?;
+    write_attribute(&mut header, "displayWindow", "box2i", &window)?;
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    write_attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_bits().to_le_bytes())?;
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_attribute(&mut header, "screenWindowWidth", "float", &1f32.to_bits().to_le_bytes())?;
+    header.push(0);
+    writer.write_all(&header)?;
+
+    // One uncompressed block per scanline: the offset table, then each block's y coordinate,
+    // size and channel data.
+    let line_size = width * 3 * bytes_per_value;
+    let table_size = height * 8;
+    for y in 0..height {
+        let offset = header.len() + table_size + y * (8 + line_size);
+        write_u64(writer, offset as u64)?;
+    }
+    let mut line: Vec<u8> = Vec::with_capacity(line_size);
+    for (y, row) in rows.iter().enumerate() {
+        line.clear();
+        for channel in 0..3 {
+            for pixel in row.iter() {
+                let value = match channel { 0 => pixel.z, 1 => pixel.y, _ => pixel.x } as f32;
+                match pixel_type {
+                    ExrPixelType::Half => {
+                        let half = f32_to_half(value);
+                        line.extend_from_slice(&[half as u8, (half >> 8) as u8]);
+                    },
+                    ExrPixelType::Float => line.extend_from_slice(&value.to_bits().to_le_bytes())
+                }
+            }
+        }
+        write_u32(writer, y as u32)?;
+        write_u32(writer, line_size as u32)?;
+        writer.write_all(&line)?;
+    }
+    Ok(())
+}
+
+fn invalid_data<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_header_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(invalid_data("Unexpected end of header"));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+// Reads a colour ("PF") or greyscale ("Pf") portable float map.
+pub fn read_pfm<R: BufRead>(reader: &mut R) -> io::Result<Vec<Vec<Vec3d>>> {
+    let channels = match read_header_line(reader)?.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid_data("Not a PFM file"))
+    };
+    // The dimensions and scale may share a line, or not.
+    let mut fields: Vec<String> = Vec::new();
+    while fields.len() < 3 {
+        fields.extend(read_header_line(reader)?.split_whitespace().map(|f| f.to_string()));
+    }
+    let parse_size = |f: &str| f.parse::<usize>().map_err(|_| invalid_data(format!("Bad PFM size '{}'", f)));
+    let (width, height) = (parse_size(&fields[0])?, parse_size(&fields[1])?);
+    let scale: f64 = fields[2].parse().map_err(|_| invalid_data(format!("Bad PFM scale '{}'", fields[2])))?;
+    let little_endian = scale < 0.0;
+    let size = width.checked_mul(height).and_then(|n| n.checked_mul(channels * 4));
+    let mut data = match size {
+        Some(size) if size > 0 => vec![0u8; size],
+        _ => return Err(invalid_data(format!("Bad PFM size {} x {}", width, height)))
+    };
+    reader.read_exact(&mut data)?;
+    let value = |index: usize| {
+        let b = &data[index * 4..index * 4 + 4];
+        let bits = if little_endian {
+            (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+        } else {
+            (b[3] as u32) | (b[2] as u32) << 8 | (b[1] as u32) << 16 | (b[0] as u32) << 24
+        };
+        f32::from_bits(bits) as f64
+    };
+    // Stored bottom row first.
+    Ok((0..height).rev().map(|y| {
+        (0..width).map(|x| {
+            let base = (y * width + x) * channels;
+            if channels == 3 {
+                Vec3d::new(value(base), value(base + 1), value(base + 2))
+            } else {
+                Vec3d::one() * value(base)
+            }
+        }).collect()
+    }).collect())
+}
+
+fn from_rgbe(rgbe: &[u8]) -> Vec3d {
+    if rgbe[3] == 0 { return Vec3d::zero(); }
+    let scale = 2f64.powi(rgbe[3] as i32 - 128 - 8);
+    Vec3d::new(rgbe[0] as f64 * scale, rgbe[1] as f64 * scale, rgbe[2] as f64 * scale)
+}
+
+// Reads one scanline of RGBE pixels, either flat or with the usual per-channel run length
+// encoding.
+fn read_rgbe_scanline<R: Read>(reader: &mut R, width: usize) -> io::Result<Vec<Vec3d>> {
+    let mut start = [0u8; 4];
+    reader.read_exact(&mut start)?;
+    let encoded = (8..32768).contains(&width) && start[0] == 2 && start[1] == 2 && start[2] & 0x80 == 0;
+    if !encoded {
+        let mut flat = vec![0u8; width * 4];
+        flat[..4].copy_from_slice(&start);
+        reader.read_exact(&mut flat[4..])?;
+        return Ok(flat.chunks(4).map(from_rgbe).collect());
+    }
+    if ((start[2] as usize) << 8 | start[3] as usize) != width {
+        return Err(invalid_data("Scanline width doesn't match the image"));
+    }
+    // Each channel is encoded separately, as runs (count > 128) or literal spans.
+    let mut channels = vec![0u8; width * 4];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            reader.read_exact(&mut count)?;
+            let (run, count) = if count[0] > 128 { (true, count[0] as usize - 128) } else { (false, count[0] as usize) };
+            if count == 0 || x + count > width {
+                return Err(invalid_data("Bad run length in scanline"));
+            }
+            if run {
+                let mut value = [0u8; 1];
+                reader.read_exact(&mut value)?;
+                for i in 0..count { channels[(x + i) * 4 + channel] = value[0]; }
+            } else {
+                let mut values = vec![0u8; count];
+                reader.read_exact(&mut values)?;
+                for i in 0..count { channels[(x + i) * 4 + channel] = values[i]; }
+            }
+            x += count;
+        }
+    }
+    Ok(channels.chunks(4).map(from_rgbe).collect())
+}
+
+// Reads a Radiance RGBE image in the standard top-to-bottom, left-to-right orientation.
+pub fn read_radiance<R: BufRead>(reader: &mut R) -> io::Result<Vec<Vec<Vec3d>>> {
+    let magic = read_header_line(reader)?;
+    if !magic.starts_with("#?") {
+        return Err(invalid_data("Not a Radiance HDR file"));
+    }
+    loop {
+        let line = read_header_line(reader)?;
+        if line.is_empty() { break; }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid_data(format!("Unsupported format '{}'", &line[7..])));
+        }
+    }
+    let resolution = read_header_line(reader)?;
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    if fields.len() != 4 || fields[0] != "-Y" || fields[2] != "+X" {
+        return Err(invalid_data(format!("Unsupported orientation '{}'", resolution)));
+    }
+    let parse_size = |f: &str| f.parse::<usize>().map_err(|_| invalid_data(format!("Bad image size '{}'", f)));
+    let (height, width) = (parse_size(fields[1])?, parse_size(fields[3])?);
+    if width == 0 || height == 0 {
+        return Err(invalid_data(format!("Bad image size '{}'", resolution)));
+    }
+    (0..height).map(|_| read_rgbe_scanline(reader, width)).collect()
+}
+
+// Loads a PFM or Radiance image, chosen by extension.
+pub fn load_hdr_image<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<Vec3d>>, LoadError> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let format = HdrFormat::from_filename(&name, ExrPixelType::Float);
+    let mut reader = BufReader::new(File::open(path).map_err(|e| LoadError::IoError(name.clone(), e))?);
+    let rows = match format {
+        Some(HdrFormat::Pfm) => read_pfm(&mut reader),
+        Some(HdrFormat::Radiance) => read_radiance(&mut reader),
+        _ => return Err(LoadError::InvalidError { file: name, message: "Expected a .pfm or .hdr image".to_string() })
+    };
+    let rows = rows.map_err(|e| LoadError::InvalidError { file: name.clone(), message: e.to_string() })?;
+    if rows.is_empty() || rows[0].is_empty() {
+        return Err(LoadError::InvalidError { file: name, message: "Image is empty".to_string() });
+    }
+    Ok(rows)
+}
+
+#[test]
+fn half_conversion() {
+    assert_eq!(f32_to_half(0.0), 0x0000);
+    assert_eq!(f32_to_half(-0.0), 0x8000);
+    assert_eq!(f32_to_half(1.0), 0x3c00);
+    assert_eq!(f32_to_half(-2.0), 0xc000);
+    assert_eq!(f32_to_half(65504.0), 0x7bff);
+    assert_eq!(f32_to_half(1e6), 0x7c00);
+    assert_eq!(f32_to_half(1.0 / 3.0), 0x3555);
+    // Smallest half denormal.
+    assert_eq!(f32_to_half(1.0 / 16777216.0), 0x0001);
+    assert_eq!(f32_to_half(1.0 + 1.0 / 2048.0), 0x3c00);
+}
+
+#[test]
+fn exr_layout() {
+    let rows = vec![vec![Vec3d::new(1.0, 2.0, 3.0); 4]; 2];
+    let mut half = Vec::new();
+    write_exr(&mut half, &rows, ExrPixelType::Half).unwrap();
+    let mut float = Vec::new();
+    write_exr(&mut float, &rows, ExrPixelType::Float).unwrap();
+    // The headers are the same size, so the difference is all pixel data.
+    assert_eq!(float.len() - half.len(), 2 * 4 * 3 * 2);
+    // The last scanline ends with the red channel of its final pixel.
+    assert_eq!(&half[half.len() - 2..], &[0x00, 0x3c]);
+    assert_eq!(&float[float.len() - 4..], &1f32.to_bits().to_le_bytes());
+    let empty: Vec<Vec<Vec3d>> = Vec::new();
+    assert_eq!(write_exr(&mut Vec::new(), &empty, ExrPixelType::Half).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn hdr_round_trip() {
+    let mut rows: Vec<Vec<Vec3d>> = (0..3).map(|y| {
+        (0..10).map(|x| Vec3d::new(x as f64 * 0.5, y as f64 * 0.25, 4.0)).collect()
+    }).collect();
+    rows[2][9] = Vec3d::new(f64::INFINITY, 1.0, 1.0);
+    let mut pfm = Vec::new();
+    write_pfm(&mut pfm, &rows).unwrap();
+    let read = read_pfm(&mut &pfm[..]).unwrap();
+    assert_eq!(read[2][7].x, 3.5);
+    assert_eq!(read[2][7].y, 0.5);
+
+    let mut hdr = Vec::new();
+    write_radiance(&mut hdr, &rows).unwrap();
+    let read = read_radiance(&mut &hdr[..]).unwrap();
+    assert_eq!(read.len(), 3);
+    assert_eq!(read[1][3].x, 1.5);
+    assert_eq!(read[1][3].z, 4.0);
+    // Non-finite pixels are written as black rather than overflowing the exponent.
+    assert_eq!(read[2][9].y, 0.0);
+
+    // A run length encoded scanline of eight pixels: a run of each channel value.
+    let mut rle = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+    rle.extend_from_slice(&[2, 2, 0, 8]);
+    rle.extend_from_slice(&[136, 128, 2, 0, 128, 134, 0, 136, 0, 136, 129]);
+    let read = read_radiance(&mut &rle[..]).unwrap();
+    assert_eq!(read[0][0].x, 1.0);
+    assert_eq!(read[0][1].y, 1.0);
+    assert_eq!(read[0][7].z, 0.0);
+}
+
+#[test]
+fn rejects_bad_sizes() {
+    assert!(read_radiance(&mut &b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 0\n"[..]).is_err());
+    assert!(read_pfm(&mut &b"PF\n0 4\n-1.0\n"[..]).is_err());
+    let huge = format!("PF\n{} 2\n-1.0\n", usize::MAX);
+    assert!(read_pfm(&mut huge.as_bytes()).is_err());
+}
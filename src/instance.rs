@@ -0,0 +1,213 @@
+// Copies of shared geometry, each placed with its own transform and optionally given its own
+// material and colour, so a mesh is only stored once however many times it appears. The
+// transform can be animated, for motion blur.
+use bvh::Aabb;
+use geometry::Ray;
+use material::Material;
+use math::{AnimatedTransform, Transform, Vec3d, F64Rng};
+use renderable::{Hit, Renderable};
+use texture::Texture;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub struct Instance {
+    object: Arc<Renderable>,
+    // From the object's space to the world, or to where the animation starts from.
+    transform: Transform,
+    inverse: Transform,
+    animation: Option<AnimatedTransform>,
+    material: Option<Material>,
+    colour: Option<Arc<Texture>>
+}
+
+impl Instance {
+    pub fn new(object: Arc<Renderable>, transform: Transform) -> Instance {
+        Instance {
+            object: object,
+            transform: transform,
+            inverse: transform.inverse(),
+            animation: None,
+            material: None,
+            colour: None
+        }
+    }
+    // Moves the instance over time, after its own transform.
+    pub fn with_animation(self, animation: AnimatedTransform) -> Instance {
+        Instance { animation: Some(animation), ..self }
+    }
+    pub fn with_material(self, material: Material) -> Instance {
+        Instance { material: Some(material), ..self }
+    }
+    pub fn with_texture(self, texture: Arc<Texture>) -> Instance {
+        Instance { colour: Some(texture), ..self }
+    }
+
+    // From the object's space to the world at `time`.
+    fn transform_at(&self, time: f64) -> Transform {
+        match self.animation {
+            Some(ref animation) => animation.at(time) * self.transform,
+            None => self.transform
+        }
+    }
+
+    // The ray in the object's space at its time, normalised as primitives expect, and how many
+    // times longer distances along it are there.
+    fn to_object(&self, ray: &Ray, transform: &Transform) -> (Ray, f64) {
+        let inverse = match self.animation {
+            Some(_) => transform.inverse(),
+            None => self.inverse
+        };
+        let direction = inverse.vector(ray.direction);
+        let stretch = direction.length();
+        (Ray::new(inverse.point(ray.origin), direction / stretch).with_time(ray.time), stretch)
+    }
+}
+
+// Converts a solid angle pdf for the object space direction into one for where it ends up: a
+// linear map A stretches solid angle around a unit direction w by |det A| / |A w|^3.
+fn world_pdf(transform: &Transform, object_direction: Vec3d, pdf: f64) -> f64 {
+    let stretch = transform.vector(object_direction).length();
+    pdf * stretch * stretch * stretch / transform.determinant().abs()
+}
+
+impl Renderable for Instance {
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        let (local, stretch) = self.to_object(ray, &self.transform_at(ray.time));
+        self.object.intersect(&local).map(|dist| dist / stretch)
+    }
+    fn get_hit(&self, ray: &Ray, dist: f64) -> Hit<'_> {
+        let transform = self.transform_at(ray.time);
+        let (local, stretch) = self.to_object(ray, &transform);
+        let hit = self.object.get_hit(&local, dist * stretch);
+        Hit {
+            pos: ray.origin + ray.direction * dist,
+            normal: transform.normal(hit.normal).normalized(),
+            geometric_normal: transform.normal(hit.geometric_normal).normalized(),
+            material: match self.material { Some(ref material) => &**material, None => hit.material },
+            // Evaluated in the object's space, so solid textures move with it.
+            colour: match self.colour { Some(ref colour) => colour.value(hit.uv, hit.pos), None => hit.colour },
+            emission: hit.emission,
+            uv: hit.uv,
+            dpdu: transform.vector(hit.dpdu),
+            dpdv: transform.vector(hit.dpdv),
+            object: self
+        }
+    }
+    fn bounding_box(&self) -> Aabb {
+        let b = self.object.bounding_box();
+        let corners: Vec<Vec3d> = (0..8).map(|i| {
+            Vec3d::new(if i & 1 == 0 { b.min.x } else { b.max.x },
+                       if i & 2 == 0 { b.min.y } else { b.max.y },
+                       if i & 4 == 0 { b.min.z } else { b.max.z })
+        }).collect();
+        let bounds_at = |transform: &Transform| {
+            Aabb::from_points(&corners.iter().map(|&c| transform.point(c)).collect::<Vec<_>>())
+        };
+        let animation = match self.animation {
+            Some(ref animation) => animation,
+            None => return bounds_at(&self.transform)
+        };
+        // Bound the box at closely spaced times between each pair of keyframes. Corners swing
+        // along arcs between them, so allow for how far those can bulge past their chords: at
+        // most a half turn between keyframes makes for steps of at most pi / STEPS.
+        const STEPS: usize = 64;
+        let keyframes = animation.keyframes();
+        let mut bounds = bounds_at(&self.transform_at(keyframes[0].time));
+        for pair in keyframes.windows(2) {
+            for step in 1..(STEPS + 1) {
+                let time = pair[0].time + (pair[1].time - pair[0].time) * step as f64 / STEPS as f64;
+                bounds = bounds.union(bounds_at(&self.transform_at(time)));
+            }
+        }
+        let bulge = bounds.extent().length() * (1.0 - (PI / (2.0 * STEPS as f64)).cos());
+        Aabb::new(bounds.min - Vec3d::one() * bulge, bounds.max + Vec3d::one() * bulge)
+    }
+    fn is_emissive(&self) -> bool { self.object.is_emissive() }
+    fn random_emission(&self, from: Vec3d, time: f64, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64) {
+        let transform = self.transform_at(time);
+        let from = match self.animation {
+            Some(_) => transform.inverse().point(from),
+            None => self.inverse.point(from)
+        };
+        let (direction, radiance, pdf) = self.object.random_emission(from, time, rng);
+        if pdf <= 0.0 { return (direction, radiance, 0.0); }
+        (transform.vector(direction).normalized(), radiance, world_pdf(&transform, direction, pdf))
+    }
+    fn emission_pdf(&self, from: Vec3d, direction: Vec3d, time: f64) -> f64 {
+        let transform = self.transform_at(time);
+        let (local, _) = self.to_object(&Ray::new(from, direction).with_time(time), &transform);
+        world_pdf(&transform, local.direction, self.object.emission_pdf(local.origin, local.direction, time))
+    }
+    fn power(&self) -> f64 {
+        // Exact for uniform scaling, and only a guide to how often to sample otherwise.
+        let transform = self.transform_at(0.0);
+        self.object.power() * transform.determinant().abs().powf(2.0 / 3.0)
+    }
+    fn identity(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+#[test]
+fn instances_match_transformed_primitives() {
+    use geometry::Sphere;
+    use material::Lambertian;
+    use math::Onb;
+    use rand::{SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let unit = Arc::new(Sphere::new(Arc::new(Lambertian), 1.0, Vec3d::zero(), Vec3d::one(), Vec3d::one()));
+    let centre = Vec3d::new(1.0, 2.0, -3.0);
+    let transform = Transform::translate(centre) * Transform::rotate(Vec3d::new(0.0, 1.0, 0.0), 1.0)
+        * Transform::scale(Vec3d::new(2.0, 2.0, 2.0));
+    let instance = Instance::new(unit.clone(), transform);
+    let sphere = Sphere::new(Arc::new(Lambertian), 2.0, centre, Vec3d::one(), Vec3d::one());
+    let from = Vec3d::new(-4.0, 3.0, 5.0);
+    for _ in 0..100 {
+        let direction = (centre - from + Vec3d::new(rng.next() - 0.5, rng.next() - 0.5, rng.next() - 0.5) * 4.0).normalized();
+        let ray = Ray::new(from, direction);
+        let dist = instance.intersect(&ray);
+        assert_eq!(dist.is_some(), sphere.intersect(&ray).is_some());
+        if let Some(dist) = dist {
+            assert!((dist - sphere.intersect(&ray).unwrap()).abs() < 1e-9);
+            assert!((instance.get_hit(&ray, dist).normal - sphere.get_hit(&ray, dist).normal).length() < 1e-9);
+            assert!((instance.emission_pdf(from, direction, 0.0) - sphere.emission_pdf(from, direction, 0.0)).abs() < 1e-9);
+        }
+    }
+    assert!((instance.power() - sphere.power()).abs() < 1e-9 * sphere.power());
+
+    // Squashed, the sampled pdfs still agree with emission_pdf, and integrate to one over the
+    // directions towards it, estimated within a cone around them all.
+    let squashed = Instance::new(unit.clone(), Transform::translate(centre) * Transform::scale(Vec3d::new(3.0, 1.0, 0.5)));
+    let to_centre = centre - from;
+    let cos_max = (1.0 - 9.0 / to_centre.length_squared()).sqrt();
+    let cone = Onb::from_w(to_centre.normalized());
+    let (samples, mut total) = (20000, 0.0);
+    for _ in 0..samples {
+        let (direction, _, pdf) = squashed.random_emission(from, 0.0, &mut rng);
+        assert!((squashed.emission_pdf(from, direction, 0.0) - pdf).abs() < 1e-6 * pdf);
+        let cos = 1.0 - rng.next() * (1.0 - cos_max);
+        let sin = (1.0 - cos * cos).sqrt();
+        let phi = 2.0 * PI * rng.next();
+        total += squashed.emission_pdf(from, cone.to_world(Vec3d::new(sin * phi.cos(), sin * phi.sin(), cos)), 0.0);
+    }
+    let integral = total / samples as f64 * 2.0 * PI * (1.0 - cos_max);
+    assert!((integral - 1.0).abs() < 0.03, "pdf integrates to {}", integral);
+
+    // Animated, it's hit where it is at the ray's time, and stays inside its bounds throughout.
+    use math::{AnimatedTransform, Keyframe};
+    let mut end = Keyframe::new(1.0);
+    end.translation = Vec3d::new(4.0, 0.0, 0.0);
+    let moving = Instance::new(unit, Transform::translate(centre))
+        .with_animation(AnimatedTransform::new(vec![Keyframe::new(0.0), end]));
+    let bounds = moving.bounding_box();
+    for &time in [0.0, 0.3, 1.0].iter() {
+        let moved = centre + Vec3d::new(4.0 * time, 0.0, 0.0);
+        let ray = Ray::new(from, (moved - from).normalized()).with_time(time);
+        let dist = moving.intersect(&ray).unwrap();
+        assert!((dist - ((moved - from).length() - 1.0)).abs() < 1e-9);
+        let hit = moving.get_hit(&ray, dist);
+        assert!(bounds.min.x <= hit.pos.x && hit.pos.x <= bounds.max.x);
+    }
+}
@@ -1,99 +1,165 @@
+extern crate image;
 extern crate rand;
 
+mod bsdf;
+mod bvh;
+mod camera;
+mod error;
 mod geometry;
+mod hdr;
+mod instance;
+mod light;
 mod material;
 mod math;
+mod medium;
+mod mesh;
+mod microfacet;
+mod obj;
 mod renderable;
+mod sampling;
 mod scene;
+mod scene_file;
+mod sky;
+mod texture;
+mod tonemap;
 
+pub use self::bsdf::*;
+pub use self::bvh::{Aabb, Bvh};
+pub use self::camera::*;
+pub use self::error::LoadError;
 pub use self::geometry::*;
-pub use self::material::Material;
+pub use self::hdr::*;
+pub use self::instance::Instance;
+pub use self::light::*;
+pub use self::material::*;
 pub use self::math::*;
+pub use self::medium::*;
+pub use self::mesh::*;
+pub use self::microfacet::{MicrofacetDistribution, MicrofacetType, ConductorPreset, CONDUCTOR_PRESETS};
+pub use self::obj::*;
+pub use self::sampling::*;
 pub use self::scene::*;
+pub use self::scene_file::*;
+pub use self::sky::*;
+pub use self::texture::*;
+pub use self::tonemap::*;
 
 
-use std::f64::consts::PI;
-
-pub fn radiance(scene: &Scene, ray: &Ray, depth: i32, rng: &mut F64Rng, emit: bool) -> Vec3d {
-    scene.intersect(&ray).map_or(Vec3d::zero(), |hit| {
-        let n1 = if hit.normal.dot(ray.direction) < 0.0 { hit.normal } else { hit.normal.neg() };
-        let mut emission = if emit { hit.emission } else { Vec3d::zero() };
-        let mut colour = hit.colour;
-        let max_reflectance = colour.max_component();
-        let depth = depth + 1;
-        if depth > 5 {
-            let rand = rng.next();
-            if rand < max_reflectance && depth < 500 {
-                // Rust's stack blows up ~600 on my machine
-                colour = colour * (1.0 / max_reflectance);
-            } else {
-                return emission;
+// Estimates the light arriving along `ray` by following a single path through the scene.
+pub fn radiance(scene: &Scene, ray: &Ray, settings: &RenderSettings, rng: &mut F64Rng) -> Vec3d {
+    let mut result = Vec3d::zero();
+    let mut throughput = Vec3d::one();
+    let mut ray = *ray;
+    let mut media = MediumStack::new(scene.medium());
+    // The pdf with which the BSDF chose the current ray's direction, and where from. It's None
+    // for camera rays and delta lobes, which light sampling can't reproduce.
+    let mut bsdf_pdf: Option<(f64, Vec3d)> = None;
+    let mut bounces = 0;
+    loop {
+        let hit = scene.intersect(&ray);
+        if let Some(medium) = media.medium() {
+            let distance = hit.as_ref().map_or(f64::INFINITY, |hit| (hit.pos - ray.origin).length());
+            let sample = medium.sample_distance(distance, rng);
+            throughput = throughput * sample.weight * media.attenuation(sample.distance.unwrap_or(distance));
+            if throughput.max_component() <= 0.0 { break; }
+            if let Some(distance) = sample.distance {
+                // Scattered by the medium, which acts much like a BSDF with the phase function.
+                if bounces >= settings.max_depth { break; }
+                if bounces >= settings.roulette_depth {
+                    let survival = throughput.max_component().min(1.0);
+                    if rng.next() >= survival { break; }
+                    throughput = throughput * (1.0 / survival);
+                }
+                let pos = ray.origin + ray.direction * distance;
+                let wo = ray.direction.neg();
+                if bounces + 1 >= settings.min_depth {
+                    result = result + throughput * scene.sample_lights(pos, ray.time, &media, settings.light_samples, rng, |wi| {
+                        let phase = medium.phase(wo, wi);
+                        (Vec3d::one() * phase, phase)
+                    });
+                }
+                let (direction, pdf) = medium.sample_phase(wo, rng);
+                bsdf_pdf = Some((pdf, pos));
+                ray = Ray::new(pos, direction).with_time(ray.time);
+                bounces += 1;
+                continue;
             }
         }
-        match *hit.material {
-            Material::Diffuse => {
-                // Get a random polar coordinate.
-                let r1 = rng.next() * 2.0 * PI;
-                let r2 = rng.next();
-                let r2s = r2.sqrt();
-                // Create a coordinate system u,v,w local to the point, where the w is the normal
-                // pointing out of the sphere and the u and v are orthonormal to w.
-                let w = n1;
-                // Pick an arbitrary non-zero preferred axis for u
-                let u = if n1.x.abs() > 0.1 { Vec3d::new(0.0, 1.0, 0.0) } else { Vec3d::new(1.0, 0.0, 0.0) }.cross(w);
-                let v = w.cross(u);
-                // construct the new direction
-                let new_dir = u * r1.cos() * r2s + v * r1.sin() * r2s + w * (1.0 - r2).sqrt();
-                let new_ray = Ray::new(hit.pos, new_dir.normalized());
-                emission = emission + colour * scene.sample_lights(hit.pos, n1, rng);
-                colour = colour * radiance(scene, &new_ray, depth, rng, false);
-            },
-            Material::Specular => {
-                let reflection = ray.direction - hit.normal * 2.0 * hit.normal.dot(ray.direction);
-                let reflected_ray = Ray::new(hit.pos, reflection);
-                colour = colour * radiance(scene, &reflected_ray, depth, rng, true);
-            },
-            Material::Refractive => {
-                let reflection = ray.direction - hit.normal * 2.0 * hit.normal.dot(ray.direction);
-                let reflected_ray = Ray::new(hit.pos, reflection);
-                let into = hit.normal.dot(n1) > 0.0;
-                let nc = 1.0;
-                let nt = 1.5;
-                let nnt = if into { nc / nt } else { nt / nc };
-                let ddn = ray.direction.dot(n1);
-                let cos2t = 1.0 - nnt * nnt * (1.0 - ddn * ddn);
-                if cos2t < 0.0 {
-                    // Total internal reflection
-                    colour = colour * radiance(scene, &reflected_ray, depth, rng, true);
-                } else {
-                    let tbd = ddn * nnt + cos2t.sqrt();
-                    let tbd = if into { tbd } else { -tbd };
-                    let tdir = (ray.direction * nnt - hit.normal * tbd).normalized();
-                    let transmitted_ray = Ray::new(hit.pos, tdir);
-                    let a = nt - nc;
-                    let b = nt + nc;
-                    let r0 = (a * a) / (b * b);
-                    let c = 1.0 - if into { -ddn } else { tdir.dot(hit.normal) };
-                    let re = r0 + (1.0 - r0) * c * c * c * c * c;
-                    let tr = 1.0 - re;
-                    let p = 0.25 + 0.5 * re;
-                    let rp = re / p;
-                    let tp = tr / (1.0 - p);
-                    colour = colour * if depth > 2 {
-                        if rng.next() < p {
-                            radiance(scene, &reflected_ray, depth, rng, true) * rp
-                        } else {
-                            radiance(scene, &transmitted_ray, depth, rng, true) * tp
-                        }
-                    } else {
-                        radiance(scene, &reflected_ray, depth, rng, true) * re +
-                            radiance(scene, &transmitted_ray, depth, rng, true) * tr
-                    }
+        let hit = match hit {
+            Some(hit) => hit,
+            None => {
+                if bounces >= settings.min_depth {
+                    result = result + throughput * scene.background(ray.direction, bsdf_pdf, settings.light_samples);
                 }
+                break;
             }
+        };
+        if media.medium().is_none() {
+            // Inside a scattering medium this was done above, over whichever distance was sampled.
+            throughput = throughput * media.attenuation((hit.pos - ray.origin).length());
+        }
+        if bounces >= settings.min_depth && hit.emission.max_component() > 0.0 {
+            // Weight against the chance of light sampling having found this emitter.
+            let weight = match bsdf_pdf {
+                None => 1.0,
+                Some((pdf, from)) => {
+                    let light_pdf = scene.light_pdf(from, ray.direction, ray.time, hit.object, settings.light_samples);
+                    power_heuristic(pdf, light_pdf)
+                }
+            };
+            result = result + throughput * hit.emission * weight;
+        }
+        let bsdf = hit.material;
+        let entering = hit.geometric_normal.dot(ray.direction) < 0.0;
+        if bsdf.flags().contains(BsdfFlags::NULL) {
+            // Carry straight on into or out of whatever it bounds. It's not a bounce, and light
+            // sampling from where the path last scattered could still find what's beyond.
+            media = media.crossing(bsdf, entering);
+            ray = Ray::new(hit.pos, ray.direction).with_time(ray.time);
+            continue;
+        }
+        if bounces >= settings.max_depth { break; }
+        let mut colour = hit.colour;
+        if bounces >= settings.roulette_depth {
+            // Russian roulette: stop dim paths early, and boost the ones that survive to keep
+            // the estimate unbiased.
+            let survival = (throughput * colour).max_component().min(1.0);
+            if rng.next() >= survival { break; }
+            colour = colour * (1.0 / survival);
+        }
+        let wo = ray.direction.neg();
+        // Going in, the outside is whatever we're currently inside; coming out, it's whatever
+        // encloses this object.
+        let outside_ior = if entering { media.ior() } else { media.leaving(bsdf).ior() };
+        // Interpolated and normal mapped shading normals can disagree with the true surface
+        // about which side a direction is on. Scattering into such directions would leak light
+        // through the surface, so they're ignored, and if wo is one of them the true normal is
+        // used instead.
+        let normal = if (hit.normal.dot(wo) > 0.0) == entering { hit.normal } else { hit.geometric_normal };
+        let agrees = |wi: Vec3d| (wi.dot(normal) > 0.0) == (wi.dot(hit.geometric_normal) > 0.0);
+        let frame = Onb::from_w_u(normal, hit.dpdu);
+        let context = BsdfContext { frame: frame, outside_ior: outside_ior, uv: hit.uv, pos: hit.pos };
+        if !bsdf.flags().contains(BsdfFlags::DELTA) && bounces + 1 >= settings.min_depth {
+            result = result + throughput * colour * scene.sample_lights(hit.pos, ray.time, &media, settings.light_samples, rng, |wi| {
+                if !agrees(wi) { return (Vec3d::zero(), 0.0); }
+                (bsdf.eval(&context, wo, wi) * wi.dot(normal).abs(), bsdf.pdf(&context, wo, wi))
+            });
+        }
+        let sample = match bsdf.sample(&context, wo, rng) {
+            Some(ref sample) if !agrees(sample.direction) => break,
+            Some(sample) => sample,
+            None => break
+        };
+        throughput = throughput * colour * sample.weight;
+        if throughput.max_component() <= 0.0 { break; }
+        if (sample.direction.dot(hit.geometric_normal) > 0.0) != entering {
+            media = media.crossing(bsdf, entering);
         }
-        emission + colour
-    })
+        bsdf_pdf = if sample.flags.contains(BsdfFlags::DELTA) { None } else { Some((sample.pdf, hit.pos)) };
+        ray = Ray::new(hit.pos, sample.direction).with_time(ray.time);
+        bounces += 1;
+    }
+    result
 }
 
 pub fn random_samp<T: F64Rng>(rng: &mut T) -> f64 {
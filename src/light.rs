@@ -0,0 +1,337 @@
+// Light sources that aren't part of the scene's geometry. Most are delta lights: they emit from
+// a single point or in a single direction, so they can only be found by sampling them, and are
+// invisible to camera rays and BSDF sampling. Environment lights surround the whole scene, and
+// are also seen by any ray that escapes it.
+use error::LoadError;
+use hdr::load_hdr_image;
+use math::{Onb, Vec3d, F64Rng};
+use sampling::Distribution2D;
+
+use std::f64;
+use std::f64::consts::PI;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LightSample {
+    // Normalized direction from the point being lit towards the light.
+    pub direction: Vec3d,
+    // How far away the light is along `direction`, for shadow testing. Infinite for lights that
+    // are infinitely far away.
+    pub distance: f64,
+    // The light arriving from `direction`.
+    pub radiance: Vec3d,
+    // The pdf of having chosen `direction`, with respect to solid angle. Delta lights have no
+    // real density, and use one.
+    pub pdf: f64
+}
+
+pub trait Light: Send + Sync {
+    // Samples the light arriving at `from`, ignoring anything in the way. Returns None if no light
+    // can arrive there.
+    fn sample(&self, from: Vec3d, rng: &mut F64Rng) -> Option<LightSample>;
+    fn is_delta(&self) -> bool { true }
+    // The pdf with which `sample` would choose `direction`; always zero for delta lights.
+    fn pdf(&self, _from: Vec3d, _direction: Vec3d) -> f64 { 0.0 }
+    // The light arriving along a ray that leaves the scene in `direction`.
+    fn background(&self, _direction: Vec3d) -> Vec3d { Vec3d::zero() }
+    // Roughly how much light (as luminance) reaches a scene of the given bounding radius, used
+    // to decide how often to sample this light relative to others.
+    fn power(&self, scene_radius: f64) -> f64;
+}
+
+// A point light radiating equally in all directions. `intensity` is the radiant intensity, and
+// the light falls off with distance^falloff; physically, falloff is 2.
+#[derive(Debug, Clone, Copy)]
+pub struct PointLight {
+    pub position: Vec3d,
+    pub intensity: Vec3d,
+    pub falloff: f64
+}
+
+impl PointLight {
+    pub fn new(position: Vec3d, intensity: Vec3d) -> PointLight {
+        PointLight { position: position, intensity: intensity, falloff: 2.0 }
+    }
+    pub fn with_falloff(self, falloff: f64) -> PointLight {
+        PointLight { falloff: falloff, ..self }
+    }
+}
+
+// Common to point and spot lights: the direction and distance to `position`, and the intensity
+// after falloff.
+fn point_sample(position: Vec3d, intensity: Vec3d, falloff: f64, from: Vec3d) -> Option<LightSample> {
+    let to_light = position - from;
+    let distance = to_light.length();
+    if distance <= 0.0 { return None; }
+    Some(LightSample {
+        direction: to_light * (1.0 / distance),
+        distance: distance,
+        radiance: intensity * (1.0 / distance.powf(falloff)),
+        pdf: 1.0
+    })
+}
+
+impl Light for PointLight {
+    fn sample(&self, from: Vec3d, _rng: &mut F64Rng) -> Option<LightSample> {
+        point_sample(self.position, self.intensity, self.falloff, from)
+    }
+    fn power(&self, _scene_radius: f64) -> f64 {
+        4.0 * PI * self.intensity.luminance()
+    }
+}
+
+// A point light restricted to a cone. It's at full intensity within `cos_inner` of its
+// direction, fading smoothly to nothing at `cos_outer`.
+#[derive(Debug, Clone, Copy)]
+pub struct SpotLight {
+    pub position: Vec3d,
+    pub direction: Vec3d,
+    pub intensity: Vec3d,
+    pub falloff: f64,
+    pub cos_inner: f64,
+    pub cos_outer: f64
+}
+
+impl SpotLight {
+    // `cone` is the half-angle of the cone in degrees, and the last `edge` degrees of it fade out.
+    pub fn new(position: Vec3d, direction: Vec3d, intensity: Vec3d, cone: f64, edge: f64) -> SpotLight {
+        SpotLight {
+            position: position,
+            direction: direction.normalized(),
+            intensity: intensity,
+            falloff: 2.0,
+            cos_inner: (cone - edge).max(0.0).to_radians().cos(),
+            cos_outer: cone.to_radians().cos()
+        }
+    }
+    pub fn with_falloff(self, falloff: f64) -> SpotLight {
+        SpotLight { falloff: falloff, ..self }
+    }
+
+    fn cone_factor(&self, cos: f64) -> f64 {
+        if cos >= self.cos_inner { return 1.0; }
+        if cos <= self.cos_outer { return 0.0; }
+        let t = (cos - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, from: Vec3d, _rng: &mut F64Rng) -> Option<LightSample> {
+        point_sample(self.position, self.intensity, self.falloff, from).and_then(|sample| {
+            let factor = self.cone_factor(sample.direction.neg().dot(self.direction));
+            if factor <= 0.0 { return None; }
+            Some(LightSample { radiance: sample.radiance * factor, ..sample })
+        })
+    }
+    fn power(&self, _scene_radius: f64) -> f64 {
+        // Counting the fading edge as half lit.
+        2.0 * PI * (1.0 - 0.5 * (self.cos_inner + self.cos_outer)) * self.intensity.luminance()
+    }
+}
+
+// Parallel light from infinitely far away, like the sun. `irradiance` is the light falling on a
+// surface facing it.
+#[derive(Debug, Clone, Copy)]
+pub struct DirectionalLight {
+    // The direction the light travels in.
+    pub direction: Vec3d,
+    pub irradiance: Vec3d
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3d, irradiance: Vec3d) -> DirectionalLight {
+        DirectionalLight { direction: direction.normalized(), irradiance: irradiance }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _from: Vec3d, _rng: &mut F64Rng) -> Option<LightSample> {
+        Some(LightSample { direction: self.direction.neg(), distance: f64::INFINITY, radiance: self.irradiance, pdf: 1.0 })
+    }
+    fn power(&self, scene_radius: f64) -> f64 {
+        PI * scene_radius * scene_radius * self.irradiance.luminance()
+    }
+}
+
+// A distant light subtending a small cone of directions, like the sun's disc. Unlike a
+// directional light it casts soft shadows, and can be seen.
+#[derive(Debug, Clone, Copy)]
+pub struct SunLight {
+    // Towards the sun.
+    direction: Vec3d,
+    radiance: Vec3d,
+    cos_max: f64
+}
+
+impl SunLight {
+    // `angular_radius` is in radians; the real sun's is about 0.00465.
+    pub fn new(direction: Vec3d, angular_radius: f64, radiance: Vec3d) -> SunLight {
+        SunLight { direction: direction.normalized(), radiance: radiance, cos_max: angular_radius.cos() }
+    }
+
+    fn solid_angle(&self) -> f64 {
+        2.0 * PI * (1.0 - self.cos_max)
+    }
+}
+
+impl Light for SunLight {
+    fn sample(&self, _from: Vec3d, rng: &mut F64Rng) -> Option<LightSample> {
+        // Uniform over the cone.
+        let cos = 1.0 - rng.next() * (1.0 - self.cos_max);
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.next();
+        let frame = Onb::from_w(self.direction);
+        Some(LightSample {
+            direction: frame.to_world(Vec3d::new(sin * phi.cos(), sin * phi.sin(), cos)).normalized(),
+            distance: f64::INFINITY,
+            radiance: self.radiance,
+            pdf: 1.0 / self.solid_angle()
+        })
+    }
+    fn is_delta(&self) -> bool { false }
+    fn pdf(&self, _from: Vec3d, direction: Vec3d) -> f64 {
+        if direction.dot(self.direction) >= self.cos_max { 1.0 / self.solid_angle() } else { 0.0 }
+    }
+    fn background(&self, direction: Vec3d) -> Vec3d {
+        if direction.dot(self.direction) >= self.cos_max { self.radiance } else { Vec3d::zero() }
+    }
+    fn power(&self, scene_radius: f64) -> f64 {
+        PI * scene_radius * scene_radius * self.solid_angle() * self.radiance.luminance()
+    }
+}
+
+// Light from an equirectangular (latitude-longitude) image surrounding the scene, with +y up.
+// The image's centre is towards -z, and `rotation` turns it about the vertical axis.
+pub struct EnvironmentLight {
+    pixels: Vec<Vec3d>,
+    width: usize,
+    height: usize,
+    rotation: f64,
+    intensity: f64,
+    // Proportional to each pixel's contribution, so bright areas like the sun get most samples.
+    distribution: Distribution2D
+}
+
+// The direction at (u, v) in an equirectangular image turned by `rotation` radians.
+fn equirect_direction(u: f64, v: f64, rotation: f64) -> Vec3d {
+    let (theta, phi) = (v * PI, u * 2.0 * PI - rotation);
+    Vec3d::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+}
+
+impl EnvironmentLight {
+    // `rows` is the image, top row first. Rotation is in radians.
+    pub fn new(rows: Vec<Vec<Vec3d>>, rotation: f64, intensity: f64) -> EnvironmentLight {
+        assert!(!rows.is_empty() && !rows[0].is_empty(), "Can't build an environment light from an empty image");
+        let (width, height) = (rows[0].len(), rows.len());
+        assert!(rows.iter().all(|row| row.len() == width), "Environment image rows must all be the same length");
+        let pixels: Vec<Vec3d> = rows.into_iter().flat_map(|row| row.into_iter()).collect();
+        // Rows near the poles cover less of the sphere.
+        let weights: Vec<f64> = pixels.iter().enumerate().map(|(i, &p)| {
+            let theta = PI * ((i / width) as f64 + 0.5) / height as f64;
+            p.luminance().max(0.0) * theta.sin()
+        }).collect();
+        EnvironmentLight {
+            distribution: Distribution2D::new(&weights, width, height),
+            pixels: pixels,
+            width: width,
+            height: height,
+            rotation: rotation,
+            intensity: intensity
+        }
+    }
+
+    // Loads a .hdr or .pfm image. Rotation is in degrees.
+    pub fn load<P: AsRef<Path>>(path: P, rotation: f64, intensity: f64) -> Result<EnvironmentLight, LoadError> {
+        Ok(EnvironmentLight::new(load_hdr_image(path)?, rotation.to_radians(), intensity))
+    }
+
+    // Bakes a function of direction into a width x height image.
+    pub fn from_function<F: Fn(Vec3d) -> Vec3d>(width: usize, height: usize, radiance: F) -> EnvironmentLight {
+        let rows = (0..height).map(|y| {
+            (0..width).map(|x| {
+                radiance(equirect_direction((x as f64 + 0.5) / width as f64, (y as f64 + 0.5) / height as f64, 0.0))
+            }).collect()
+        }).collect();
+        EnvironmentLight::new(rows, 0.0, 1.0)
+    }
+
+    // The image coordinates, in [0, 1)^2, of a direction.
+    fn to_image(&self, direction: Vec3d) -> (f64, f64) {
+        let theta = direction.y.clamp(-1.0, 1.0).acos();
+        let phi = direction.x.atan2(-direction.z) + self.rotation;
+        let u = phi / (2.0 * PI);
+        (u - u.floor(), theta / PI)
+    }
+
+
+    fn lookup(&self, u: f64, v: f64) -> Vec3d {
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+}
+
+impl Light for EnvironmentLight {
+    fn sample(&self, _from: Vec3d, rng: &mut F64Rng) -> Option<LightSample> {
+        let ((u, v), pdf) = self.distribution.sample_continuous(rng.next(), rng.next());
+        let sin_theta = (v * PI).sin();
+        if pdf <= 0.0 || sin_theta <= 0.0 { return None; }
+        Some(LightSample {
+            direction: equirect_direction(u, v, self.rotation),
+            distance: f64::INFINITY,
+            radiance: self.lookup(u, v),
+            // The image covers 2 pi by pi radians, and each row is squashed by sin(theta).
+            pdf: pdf / (2.0 * PI * PI * sin_theta)
+        })
+    }
+    fn is_delta(&self) -> bool { false }
+    fn pdf(&self, _from: Vec3d, direction: Vec3d) -> f64 {
+        let (u, v) = self.to_image(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 { 0.0 } else { self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta) }
+    }
+    fn background(&self, direction: Vec3d) -> Vec3d {
+        let (u, v) = self.to_image(direction);
+        self.lookup(u, v)
+    }
+    fn power(&self, scene_radius: f64) -> f64 {
+        // The distribution integrates luminance over the image; the sphere is 2 pi^2 times bigger.
+        PI * scene_radius * scene_radius * 2.0 * PI * PI * self.distribution.integral() * self.intensity
+    }
+}
+
+#[test]
+fn environment_sampling_matches_pdf() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    // A dim sky with one bright pixel.
+    let mut rows = vec![vec![Vec3d::one() * 0.1; 16]; 8];
+    rows[2][5] = Vec3d::one() * 100.0;
+    let light = EnvironmentLight::new(rows, 0.7, 2.0);
+    let mut bright = 0;
+    for _ in 0..1000 {
+        let sample = light.sample(Vec3d::zero(), &mut rng).unwrap();
+        assert!((light.pdf(Vec3d::zero(), sample.direction) - sample.pdf).abs() < 1e-6 * sample.pdf);
+        assert_eq!(light.background(sample.direction).x, sample.radiance.x);
+        if sample.radiance.x == 200.0 { bright += 1; }
+    }
+    assert!(bright > 900);
+}
+
+#[test]
+fn spot_light_cone() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let spot = SpotLight::new(Vec3d::new(0.0, 10.0, 0.0), Vec3d::new(0.0, -1.0, 0.0), Vec3d::one(), 30.0, 10.0);
+    // Directly below, at full intensity with inverse square falloff.
+    let below = spot.sample(Vec3d::zero(), &mut rng).unwrap();
+    assert!((below.radiance.x - 0.01).abs() < 1e-12);
+    assert_eq!(below.distance, 10.0);
+    // In the soft edge, 25 degrees off axis.
+    let edge = spot.sample(Vec3d::new(10.0 * 25f64.to_radians().tan(), 0.0, 0.0), &mut rng).unwrap();
+    let unattenuated = 1.0 / edge.distance.powi(2);
+    assert!(edge.radiance.x > 0.0 && edge.radiance.x < unattenuated);
+    // Outside the cone entirely.
+    assert!(spot.sample(Vec3d::new(10.0, 0.0, 0.0), &mut rng).is_none());
+}
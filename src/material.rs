@@ -1,5 +1,386 @@
-pub enum Material {
-    Diffuse,
-    Specular,
-    Refractive
+// The built-in materials. Objects hold their material as a shared Bsdf, so anything
+// implementing the trait can be used in their place.
+use bsdf::{Bsdf, BsdfContext, BsdfFlags, BsdfSample, Interior};
+use math::{Onb, Vec3d, F64Rng};
+use medium::Medium;
+use microfacet::{conductor_preset, fresnel_conductor, fresnel_dielectric, reflect, refract,
+                 MicrofacetDistribution, MicrofacetType};
+use texture::Texture;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub type Material = Arc<Bsdf>;
+
+// The shading frame turned to the side of the surface that wo is on, with that side's normal
+// along w. Its u axis stays put.
+fn facing_frame(frame: &Onb, wo: Vec3d) -> Onb {
+    if frame.w.dot(wo) < 0.0 { Onb { u: frame.u, v: frame.v.neg(), w: frame.w.neg() } } else { *frame }
+}
+
+fn same_side(normal: Vec3d, wo: Vec3d, wi: Vec3d) -> bool {
+    normal.dot(wo) * normal.dot(wi) > 0.0
+}
+
+// A perfectly matte surface, scattering light equally in all directions.
+#[derive(Debug, Clone, Copy)]
+pub struct Lambertian;
+
+impl Bsdf for Lambertian {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::REFLECTION | BsdfFlags::DIFFUSE
+    }
+    fn eval(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> Vec3d {
+        if same_side(context.frame.w, wo, wi) { Vec3d::one() * (1.0 / PI) } else { Vec3d::zero() }
+    }
+    fn sample(&self, context: &BsdfContext, wo: Vec3d, rng: &mut F64Rng) -> Option<BsdfSample> {
+        // Cosine weighted, so the weight is always one.
+        let frame = facing_frame(&context.frame, wo);
+        let r1 = rng.next() * 2.0 * PI;
+        let r2 = rng.next();
+        let r2s = r2.sqrt();
+        let cos = (1.0 - r2).sqrt();
+        let direction = frame.to_world(Vec3d::new(r1.cos() * r2s, r1.sin() * r2s, cos)).normalized();
+        Some(BsdfSample {
+            direction: direction,
+            weight: Vec3d::one(),
+            pdf: cos / PI,
+            flags: self.flags()
+        })
+    }
+    fn pdf(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> f64 {
+        if same_side(context.frame.w, wo, wi) { wi.dot(context.frame.w).abs() / PI } else { 0.0 }
+    }
+}
+
+// A perfect mirror.
+#[derive(Debug, Clone, Copy)]
+pub struct Mirror;
+
+impl Bsdf for Mirror {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::REFLECTION | BsdfFlags::DELTA
+    }
+    fn eval(&self, _context: &BsdfContext, _wo: Vec3d, _wi: Vec3d) -> Vec3d {
+        Vec3d::zero()
+    }
+    fn sample(&self, context: &BsdfContext, wo: Vec3d, _rng: &mut F64Rng) -> Option<BsdfSample> {
+        Some(BsdfSample {
+            direction: reflect(wo, context.frame.w),
+            weight: Vec3d::one(),
+            pdf: 1.0,
+            flags: self.flags()
+        })
+    }
+    fn pdf(&self, _context: &BsdfContext, _wo: Vec3d, _wi: Vec3d) -> f64 {
+        0.0
+    }
+}
+
+// A smooth dielectric such as glass or water.
+#[derive(Debug, Clone, Copy)]
+pub struct Dielectric {
+    pub ior: f64,
+    pub absorption: Vec3d
+}
+
+impl Dielectric {
+    pub fn new(ior: f64, absorption: Vec3d) -> Dielectric {
+        Dielectric { ior: ior, absorption: absorption }
+    }
+    pub fn glass() -> Dielectric {
+        Dielectric::new(1.5, Vec3d::zero())
+    }
+}
+
+// The indices of refraction on wo's side of the surface and the other side.
+fn refractive_indices(context: &BsdfContext, ior: f64, wo: Vec3d) -> (f64, f64) {
+    if context.frame.w.dot(wo) > 0.0 { (context.outside_ior, ior) } else { (ior, context.outside_ior) }
+}
+
+impl Bsdf for Dielectric {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::REFLECTION | BsdfFlags::TRANSMISSION | BsdfFlags::DELTA
+    }
+    fn eval(&self, _context: &BsdfContext, _wo: Vec3d, _wi: Vec3d) -> Vec3d {
+        Vec3d::zero()
+    }
+    fn sample(&self, context: &BsdfContext, wo: Vec3d, rng: &mut F64Rng) -> Option<BsdfSample> {
+        let (eta_i, eta_t) = refractive_indices(context, self.ior, wo);
+        let n = facing_frame(&context.frame, wo).w;
+        // Choose between reflection and refraction in proportion to the Fresnel term, which then
+        // cancels out of the weight.
+        let reflectance = fresnel_dielectric(wo.dot(n), eta_i, eta_t);
+        let (direction, pdf, lobe) = if rng.next() < reflectance {
+            (reflect(wo, n), reflectance, BsdfFlags::REFLECTION)
+        } else {
+            match refract(wo, n, eta_i / eta_t) {
+                Some(direction) => (direction.normalized(), 1.0 - reflectance, BsdfFlags::TRANSMISSION),
+                None => return None
+            }
+        };
+        Some(BsdfSample { direction: direction, weight: Vec3d::one(), pdf: pdf, flags: lobe | BsdfFlags::DELTA })
+    }
+    fn pdf(&self, _context: &BsdfContext, _wo: Vec3d, _wi: Vec3d) -> f64 {
+        0.0
+    }
+    fn interior(&self) -> Option<Interior> {
+        Some(Interior { ior: self.ior, absorption: self.absorption, medium: None })
+    }
+}
+
+// The distribution at the point being shaded: as given, or with its roughness taken from the
+// luminance of a texture.
+fn textured_distribution(distribution: MicrofacetDistribution, roughness: &Option<Arc<Texture>>,
+                         context: &BsdfContext) -> MicrofacetDistribution {
+    match *roughness {
+        Some(ref texture) => {
+            let roughness = texture.value(context.uv, context.pos).luminance().clamp(0.0, 1.0);
+            MicrofacetDistribution::new(distribution.kind, roughness)
+        },
+        None => distribution
+    }
+}
+
+// A rough metal, with a complex index of refraction eta + ik per channel.
+#[derive(Clone)]
+pub struct RoughConductor {
+    pub distribution: MicrofacetDistribution,
+    pub eta: Vec3d,
+    pub k: Vec3d,
+    pub roughness: Option<Arc<Texture>>
+}
+
+impl RoughConductor {
+    pub fn new(distribution: MicrofacetDistribution, eta: Vec3d, k: Vec3d) -> RoughConductor {
+        RoughConductor { distribution: distribution, eta: eta, k: k, roughness: None }
+    }
+
+    // Varies the roughness over the surface, overriding the distribution's.
+    pub fn with_roughness_texture(self, texture: Arc<Texture>) -> RoughConductor {
+        RoughConductor { roughness: Some(texture), ..self }
+    }
+
+    // A GGX conductor using one of the named presets (gold, copper, aluminium or silver).
+    pub fn metal(name: &str, roughness: f64) -> Option<RoughConductor> {
+        conductor_preset(name).map(|preset| {
+            RoughConductor::new(MicrofacetDistribution::new(MicrofacetType::Ggx, roughness), preset.eta, preset.k)
+        })
+    }
+
+    fn fresnel(&self, cos: f64) -> Vec3d {
+        Vec3d::new(fresnel_conductor(cos, self.eta.x, self.k.x),
+                   fresnel_conductor(cos, self.eta.y, self.k.y),
+                   fresnel_conductor(cos, self.eta.z, self.k.z))
+    }
+}
+
+impl Bsdf for RoughConductor {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::REFLECTION | BsdfFlags::GLOSSY
+    }
+    fn eval(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> Vec3d {
+        let frame = facing_frame(&context.frame, wo);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z <= 0.0 { return Vec3d::zero(); }
+        let h = (wo + wi).normalized();
+        let d = textured_distribution(self.distribution, &self.roughness, context);
+        self.fresnel(wo.dot(h)) * (d.d(h) * d.g(wo, wi) / (4.0 * wo.z * wi.z))
+    }
+    fn sample(&self, context: &BsdfContext, wo: Vec3d, rng: &mut F64Rng) -> Option<BsdfSample> {
+        let frame = facing_frame(&context.frame, wo);
+        let wo = frame.to_local(wo);
+        if wo.z <= 0.0 { return None; }
+        let d = textured_distribution(self.distribution, &self.roughness, context);
+        let m = d.sample_normal(wo, rng);
+        let wi = reflect(wo, m);
+        let cos = wo.dot(m);
+        // Reflecting into the surface means being shadowed by the rest of the microsurface.
+        if wi.z <= 0.0 || cos <= 0.0 { return None; }
+        Some(BsdfSample {
+            direction: frame.to_world(wi),
+            weight: self.fresnel(cos) * d.sample_weight(wo, wi, m),
+            pdf: d.pdf(wo, m) / (4.0 * cos),
+            flags: self.flags()
+        })
+    }
+    fn pdf(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> f64 {
+        let frame = facing_frame(&context.frame, wo);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z <= 0.0 { return 0.0; }
+        let h = (wo + wi).normalized();
+        textured_distribution(self.distribution, &self.roughness, context).pdf(wo, h) / (4.0 * wo.dot(h))
+    }
+}
+
+// A rough dielectric, like frosted glass. Like the smooth dielectric, this leaves out the
+// 1 / eta^2 scaling of radiance crossing the boundary, which cancels out for closed objects.
+#[derive(Clone)]
+pub struct RoughDielectric {
+    pub distribution: MicrofacetDistribution,
+    pub ior: f64,
+    pub absorption: Vec3d,
+    pub roughness: Option<Arc<Texture>>
+}
+
+impl RoughDielectric {
+    pub fn new(distribution: MicrofacetDistribution, ior: f64, absorption: Vec3d) -> RoughDielectric {
+        RoughDielectric { distribution: distribution, ior: ior, absorption: absorption, roughness: None }
+    }
+
+    // Varies the roughness over the surface, overriding the distribution's.
+    pub fn with_roughness_texture(self, texture: Arc<Texture>) -> RoughDielectric {
+        RoughDielectric { roughness: Some(texture), ..self }
+    }
+
+    pub fn frosted_glass(roughness: f64) -> RoughDielectric {
+        RoughDielectric::new(MicrofacetDistribution::new(MicrofacetType::Ggx, roughness), 1.5, Vec3d::zero())
+    }
+
+    // The density of refracting through the microfacet m, with respect to wi.
+    fn transmission_pdf(d: &MicrofacetDistribution, wo: Vec3d, wi: Vec3d, m: Vec3d, eta: f64) -> f64 {
+        let denom = wo.dot(m) + eta * wi.dot(m);
+        d.pdf(wo, m) * eta * eta * wi.dot(m).abs() / (denom * denom)
+    }
+}
+
+impl Bsdf for RoughDielectric {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::REFLECTION | BsdfFlags::TRANSMISSION | BsdfFlags::GLOSSY
+    }
+    fn eval(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> Vec3d {
+        let (eta_i, eta_t) = refractive_indices(context, self.ior, wo);
+        let frame = facing_frame(&context.frame, wo);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z == 0.0 { return Vec3d::zero(); }
+        let d = textured_distribution(self.distribution, &self.roughness, context);
+        if wi.z > 0.0 {
+            let h = (wo + wi).normalized();
+            let f = fresnel_dielectric(wo.dot(h), eta_i, eta_t) * d.d(h) * d.g(wo, wi) / (4.0 * wo.z * wi.z);
+            return Vec3d::one() * f;
+        }
+        let eta = eta_t / eta_i;
+        let h = (wo + wi * eta).normalized();
+        let h = if h.z < 0.0 { h.neg() } else { h };
+        if wo.dot(h) <= 0.0 || wi.dot(h) >= 0.0 { return Vec3d::zero(); }
+        let denom = wo.dot(h) + eta * wi.dot(h);
+        let f = (1.0 - fresnel_dielectric(wo.dot(h), eta_i, eta_t)) * d.d(h) * d.g(wo, wi) * eta * eta
+            * wi.dot(h).abs() * wo.dot(h) / (wo.z * wi.z.abs() * denom * denom);
+        Vec3d::one() * f
+    }
+    fn sample(&self, context: &BsdfContext, wo: Vec3d, rng: &mut F64Rng) -> Option<BsdfSample> {
+        let (eta_i, eta_t) = refractive_indices(context, self.ior, wo);
+        let frame = facing_frame(&context.frame, wo);
+        let wo = frame.to_local(wo);
+        if wo.z <= 0.0 { return None; }
+        let d = textured_distribution(self.distribution, &self.roughness, context);
+        let m = d.sample_normal(wo, rng);
+        let cos = wo.dot(m);
+        if cos <= 0.0 { return None; }
+        // As for smooth dielectrics, the Fresnel term cancels out of the weight.
+        let reflectance = fresnel_dielectric(cos, eta_i, eta_t);
+        let (wi, pdf, lobe) = if rng.next() < reflectance {
+            let wi = reflect(wo, m);
+            if wi.z <= 0.0 { return None; }
+            (wi, reflectance * d.pdf(wo, m) / (4.0 * cos), BsdfFlags::REFLECTION)
+        } else {
+            let wi = match refract(wo, m, eta_i / eta_t) {
+                Some(wi) if wi.z < 0.0 => wi,
+                _ => return None
+            };
+            let pdf = (1.0 - reflectance) * RoughDielectric::transmission_pdf(&d, wo, wi, m, eta_t / eta_i);
+            (wi, pdf, BsdfFlags::TRANSMISSION)
+        };
+        Some(BsdfSample {
+            direction: frame.to_world(wi).normalized(),
+            weight: Vec3d::one() * d.sample_weight(wo, wi, m),
+            pdf: pdf,
+            flags: lobe | BsdfFlags::GLOSSY
+        })
+    }
+    fn pdf(&self, context: &BsdfContext, wo: Vec3d, wi: Vec3d) -> f64 {
+        let (eta_i, eta_t) = refractive_indices(context, self.ior, wo);
+        let frame = facing_frame(&context.frame, wo);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z == 0.0 { return 0.0; }
+        let d = textured_distribution(self.distribution, &self.roughness, context);
+        if wi.z > 0.0 {
+            let h = (wo + wi).normalized();
+            let reflectance = fresnel_dielectric(wo.dot(h), eta_i, eta_t);
+            return reflectance * d.pdf(wo, h) / (4.0 * wo.dot(h));
+        }
+        let eta = eta_t / eta_i;
+        let h = (wo + wi * eta).normalized();
+        let h = if h.z < 0.0 { h.neg() } else { h };
+        if wo.dot(h) <= 0.0 || wi.dot(h) >= 0.0 { return 0.0; }
+        (1.0 - fresnel_dielectric(wo.dot(h), eta_i, eta_t)) * RoughDielectric::transmission_pdf(&d, wo, wi, h, eta)
+    }
+    fn interior(&self) -> Option<Interior> {
+        Some(Interior { ior: self.ior, absorption: self.absorption, medium: None })
+    }
+}
+
+// The invisible surface of a medium such as smoke: light passes straight through it, and is
+// absorbed and scattered inside as the medium says. As far as refraction goes, it's full of air.
+#[derive(Debug, Clone, Copy)]
+pub struct MediumBoundary {
+    pub medium: Medium
+}
+
+impl MediumBoundary {
+    pub fn new(medium: Medium) -> MediumBoundary {
+        MediumBoundary { medium: medium }
+    }
+}
+
+impl Bsdf for MediumBoundary {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::TRANSMISSION | BsdfFlags::DELTA | BsdfFlags::NULL
+    }
+    fn eval(&self, _context: &BsdfContext, _wo: Vec3d, _wi: Vec3d) -> Vec3d {
+        Vec3d::zero()
+    }
+    fn sample(&self, _context: &BsdfContext, wo: Vec3d, _rng: &mut F64Rng) -> Option<BsdfSample> {
+        Some(BsdfSample { direction: wo.neg(), weight: Vec3d::one(), pdf: 1.0, flags: self.flags() })
+    }
+    fn pdf(&self, _context: &BsdfContext, _wo: Vec3d, _wi: Vec3d) -> f64 {
+        0.0
+    }
+    fn interior(&self) -> Option<Interior> {
+        Some(Interior { ior: 1.0, absorption: Vec3d::zero(), medium: Some(self.medium) })
+    }
+}
+
+#[test]
+fn sampled_weights_match_eval() {
+    use rand::{SeedableRng, XorShiftRng};
+    use texture::constant_texture;
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    // A tangent that isn't along any axis, which isotropic materials shouldn't care about.
+    let frame = Onb::from_w_u(Vec3d::new(0.0, 0.0, 1.0), Vec3d::new(1.0, 1.0, 0.0));
+    let context = BsdfContext { frame: frame, outside_ior: 1.0, uv: (0.5, 0.5), pos: Vec3d::zero() };
+    let rough = MicrofacetDistribution::new(MicrofacetType::Ggx, 0.6);
+    let bsdfs: Vec<Box<Bsdf>> = vec![
+        Box::new(Lambertian),
+        Box::new(RoughConductor::metal("copper", 0.6).unwrap()),
+        Box::new(RoughConductor::metal("gold", 0.1).unwrap()
+                 .with_roughness_texture(constant_texture(Vec3d::one() * 0.4))),
+        Box::new(RoughDielectric::new(rough, 1.5, Vec3d::zero()))
+    ];
+    for bsdf in bsdfs.iter() {
+        // From both sides, for dielectrics.
+        for &wo in [Vec3d::new(0.3, -0.2, 0.9).normalized(), Vec3d::new(0.1, 0.4, -0.8).normalized()].iter() {
+            for _ in 0..200 {
+                let sample = match bsdf.sample(&context, wo, &mut rng) {
+                    Some(sample) => sample,
+                    None => continue
+                };
+                let wi = sample.direction;
+                let pdf = bsdf.pdf(&context, wo, wi);
+                assert!((pdf - sample.pdf).abs() <= 1e-6 * pdf.max(1.0), "{} vs {}", pdf, sample.pdf);
+                let expected = bsdf.eval(&context, wo, wi) * (wi.dot(context.frame.w).abs() / pdf);
+                assert!((expected - sample.weight).abs().max_component() < 1e-6);
+            }
+        }
+    }
 }
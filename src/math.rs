@@ -58,19 +58,24 @@ impl Vec3d {
     }
     #[inline]
     pub fn max_component(self) -> f64 {
-        if self.x > self.y && self.x > self.z { self.x } else if self.y > self.x && self.y > self.z { self.y } else { self.z }
+        self.x.max(self.y).max(self.z)
     }
     #[inline]
     pub fn max_ordinal(self) -> u8 {
-        if self.x > self.y && self.x > self.z { 0 } else if self.y > self.x && self.y > self.z { 1 } else { 2 }
+        if self.x >= self.y && self.x >= self.z { 0 } else if self.y >= self.z { 1 } else { 2 }
     }
     #[inline]
     pub fn min_component(self) -> f64 {
-        if self.x < self.y && self.x < self.z { self.x } else if self.y < self.x && self.y < self.z { self.y } else { self.z }
+        self.x.min(self.y).min(self.z)
     }
     #[inline]
     pub fn min_ordinal(self) -> u8 {
-        if self.x < self.y && self.x < self.z { 0 } else if self.y < self.x && self.y < self.z { 1 } else { 2 }
+        if self.x <= self.y && self.x <= self.z { 0 } else if self.y <= self.z { 1 } else { 2 }
+    }
+    // The perceived brightness of a linear sRGB colour.
+    #[inline]
+    pub fn luminance(self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
     }
     #[inline]
     pub fn abs(self) -> Vec3d {
@@ -82,7 +87,7 @@ impl Vec3d {
     }
     #[inline]
     pub fn clamp(self) -> Vec3d {
-        Vec3d { x: self.x.clamp(), y: self.y.clamp(), z: self.z.clamp() }
+        Vec3d { x: Clamp::clamp(self.x), y: Clamp::clamp(self.y), z: Clamp::clamp(self.z) }
     }
     #[inline]
     pub fn min(self, other: Vec3d) -> Vec3d {
@@ -150,6 +155,338 @@ impl Div<f64> for Vec3d {
     }
 }
 
+// An orthonormal basis: a local frame with `w` as its "up", often a surface normal. u x v = w.
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    pub u: Vec3d,
+    pub v: Vec3d,
+    pub w: Vec3d
+}
+
+impl Onb {
+    // Any frame around the unit vector w, without branching on its direction (Duff et al.,
+    // "Building an Orthonormal Basis, Revisited", 2017).
+    pub fn from_w(w: Vec3d) -> Onb {
+        let sign = 1f64.copysign(w.z);
+        let a = -1.0 / (sign + w.z);
+        let b = w.x * w.y * a;
+        Onb {
+            u: Vec3d::new(1.0 + sign * w.x * w.x * a, sign * b, -sign * w.x),
+            v: Vec3d::new(b, sign + w.y * w.y * a, -w.y),
+            w: w
+        }
+    }
+
+    // The frame around w whose u is as close as possible to the given direction, like a
+    // surface's tangent. Falls back to any frame if u is (nearly) parallel to w.
+    pub fn from_w_u(w: Vec3d, u: Vec3d) -> Onb {
+        let u = u - w * w.dot(u);
+        if u.length_squared() < 1e-12 { return Onb::from_w(w); }
+        let u = u.normalized();
+        Onb { u: u, v: w.cross(u), w: w }
+    }
+
+    pub fn to_local(&self, a: Vec3d) -> Vec3d {
+        Vec3d::new(a.dot(self.u), a.dot(self.v), a.dot(self.w))
+    }
+
+    pub fn to_world(&self, a: Vec3d) -> Vec3d {
+        self.u * a.x + self.v * a.y + self.w * a.z
+    }
+}
+
+pub type Matrix4 = [[f64; 4]; 4];
+
+const IDENTITY: Matrix4 = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+
+fn multiply(a: &Matrix4, b: &Matrix4) -> Matrix4 {
+    let mut product = [[0.0; 4]; 4];
+    for i in 0..4 {
+        for j in 0..4 {
+            product[i][j] = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    product
+}
+
+// Gauss-Jordan elimination with partial pivoting. None if the matrix is singular, or has
+// infinities or NaNs in it.
+fn invert(m: &Matrix4) -> Option<Matrix4> {
+    let (mut a, mut inverse) = (*m, IDENTITY);
+    for column in 0..4 {
+        let pivot = (column..4).max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs())).unwrap();
+        if !a[pivot][column].is_finite() || a[pivot][column].abs() < 1e-12 { return None; }
+        a.swap(column, pivot);
+        inverse.swap(column, pivot);
+        let scale = 1.0 / a[column][column];
+        for j in 0..4 {
+            a[column][j] *= scale;
+            inverse[column][j] *= scale;
+        }
+        for i in 0..4 {
+            if i == column { continue; }
+            let factor = a[i][column];
+            for j in 0..4 {
+                a[i][j] -= factor * a[column][j];
+                inverse[i][j] -= factor * inverse[column][j];
+            }
+        }
+    }
+    Some(inverse)
+}
+
+// An affine transform: a 4x4 matrix acting on column vectors, kept alongside its inverse so
+// neither ever needs inverting again. `a * b` applies b first, then a.
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    matrix: Matrix4,
+    inverse: Matrix4
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform { matrix: IDENTITY, inverse: IDENTITY }
+    }
+
+    // Any invertible matrix, row by row. None if it can't be inverted.
+    pub fn new(matrix: Matrix4) -> Option<Transform> {
+        invert(&matrix).map(|inverse| Transform { matrix: matrix, inverse: inverse })
+    }
+
+    pub fn translate(offset: Vec3d) -> Transform {
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        for (i, &d) in [offset.x, offset.y, offset.z].iter().enumerate() {
+            matrix[i][3] = d;
+            inverse[i][3] = -d;
+        }
+        Transform { matrix: matrix, inverse: inverse }
+    }
+
+    // Scales by different factors along each axis. None of them can be zero.
+    pub fn scale(factors: Vec3d) -> Transform {
+        let mut matrix = IDENTITY;
+        let mut inverse = IDENTITY;
+        for (i, &f) in [factors.x, factors.y, factors.z].iter().enumerate() {
+            matrix[i][i] = f;
+            inverse[i][i] = 1.0 / f;
+        }
+        Transform { matrix: matrix, inverse: inverse }
+    }
+
+    // Rotates by `angle` radians about `axis`, anticlockwise when looking back down it.
+    pub fn rotate(axis: Vec3d, angle: f64) -> Transform {
+        let a = axis.normalized();
+        let (sin, cos) = angle.sin_cos();
+        let t = 1.0 - cos;
+        let matrix = [[t * a.x * a.x + cos, t * a.x * a.y - sin * a.z, t * a.x * a.z + sin * a.y, 0.0],
+                      [t * a.x * a.y + sin * a.z, t * a.y * a.y + cos, t * a.y * a.z - sin * a.x, 0.0],
+                      [t * a.x * a.z - sin * a.y, t * a.y * a.z + sin * a.x, t * a.z * a.z + cos, 0.0],
+                      [0.0, 0.0, 0.0, 1.0]];
+        // Rotations are orthogonal, so the inverse is the transpose.
+        let mut inverse = IDENTITY;
+        for i in 0..3 {
+            for j in 0..3 {
+                inverse[i][j] = matrix[j][i];
+            }
+        }
+        Transform { matrix: matrix, inverse: inverse }
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform { matrix: self.inverse, inverse: self.matrix }
+    }
+
+    pub fn matrix(&self) -> Matrix4 {
+        self.matrix
+    }
+
+    pub fn point(&self, p: Vec3d) -> Vec3d {
+        let m = &self.matrix;
+        Vec3d::new(m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+                   m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+                   m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3])
+    }
+
+    // Directions and offsets, which aren't moved by translation.
+    pub fn vector(&self, v: Vec3d) -> Vec3d {
+        let m = &self.matrix;
+        Vec3d::new(m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+                   m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+                   m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z)
+    }
+
+    // Surface normals, which have to stay perpendicular to transformed tangents and so go
+    // through the inverse transpose. The result isn't normalised.
+    pub fn normal(&self, n: Vec3d) -> Vec3d {
+        let m = &self.inverse;
+        Vec3d::new(m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+                   m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+                   m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z)
+    }
+
+    // How much the transform scales volumes by, negative if it mirrors.
+    pub fn determinant(&self) -> f64 {
+        let m = &self.matrix;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+}
+
+impl Mul for Transform {
+    type Output = Transform;
+    fn mul(self, other: Transform) -> Transform {
+        Transform { matrix: multiply(&self.matrix, &other.matrix), inverse: multiply(&other.inverse, &self.inverse) }
+    }
+}
+
+// A unit quaternion, representing a rotation. `a * b` rotates by b first, then a.
+#[derive(Debug, Clone, Copy)]
+pub struct Quaternion {
+    pub w: f64,
+    pub v: Vec3d
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion { w: 1.0, v: Vec3d::zero() }
+    }
+
+    // Rotates by `angle` radians about `axis`, the same way as Transform::rotate.
+    pub fn from_axis_angle(axis: Vec3d, angle: f64) -> Quaternion {
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        Quaternion { w: cos, v: axis.normalized() * sin }
+    }
+
+    // The rotation taking the x, y and z axes to the given orthonormal, right handed vectors.
+    pub fn from_basis(x: Vec3d, y: Vec3d, z: Vec3d) -> Quaternion {
+        // Shepperd's method: work from the largest of the four components, for accuracy.
+        let trace = x.x + y.y + z.z;
+        let q = if trace > 0.0 {
+            let s = 2.0 * (1.0 + trace).sqrt();
+            Quaternion { w: s / 4.0, v: Vec3d::new(y.z - z.y, z.x - x.z, x.y - y.x) / s }
+        } else if x.x > y.y && x.x > z.z {
+            let s = 2.0 * (1.0 + x.x - y.y - z.z).sqrt();
+            Quaternion { w: (y.z - z.y) / s, v: Vec3d::new(s / 4.0, (y.x + x.y) / s, (z.x + x.z) / s) }
+        } else if y.y > z.z {
+            let s = 2.0 * (1.0 + y.y - x.x - z.z).sqrt();
+            Quaternion { w: (z.x - x.z) / s, v: Vec3d::new((y.x + x.y) / s, s / 4.0, (z.y + y.z) / s) }
+        } else {
+            let s = 2.0 * (1.0 + z.z - x.x - y.y).sqrt();
+            Quaternion { w: (x.y - y.x) / s, v: Vec3d::new((z.x + x.z) / s, (z.y + y.z) / s, s / 4.0) }
+        };
+        q.normalized()
+    }
+
+    pub fn dot(self, other: Quaternion) -> f64 {
+        self.w * other.w + self.v.dot(other.v)
+    }
+
+    pub fn normalized(self) -> Quaternion {
+        let length = self.dot(self).sqrt();
+        Quaternion { w: self.w / length, v: self.v / length }
+    }
+
+    pub fn rotate(self, a: Vec3d) -> Vec3d {
+        let t = self.v.cross(a) * 2.0;
+        a + t * self.w + self.v.cross(t)
+    }
+
+    // Spherical linear interpolation, turning at a constant rate the short way round.
+    pub fn slerp(self, other: Quaternion, t: f64) -> Quaternion {
+        let mut cos = self.dot(other);
+        // q and -q are the same rotation; pick whichever is nearer.
+        let other = if cos < 0.0 {
+            cos = -cos;
+            Quaternion { w: -other.w, v: other.v.neg() }
+        } else {
+            other
+        };
+        let (a, b) = if cos > 0.9995 {
+            // Nearly the same, where the sines below lose precision: lerp instead.
+            (1.0 - t, t)
+        } else {
+            let angle = cos.acos();
+            let sin = angle.sin();
+            (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+        };
+        Quaternion { w: self.w * a + other.w * b, v: self.v * a + other.v * b }.normalized()
+    }
+
+    pub fn to_transform(self) -> Transform {
+        let (x, y, z) = (self.rotate(Vec3d::new(1.0, 0.0, 0.0)), self.rotate(Vec3d::new(0.0, 1.0, 0.0)),
+                         self.rotate(Vec3d::new(0.0, 0.0, 1.0)));
+        let matrix = [[x.x, y.x, z.x, 0.0], [x.y, y.y, z.y, 0.0], [x.z, y.z, z.z, 0.0], [0.0, 0.0, 0.0, 1.0]];
+        let inverse = [[x.x, x.y, x.z, 0.0], [y.x, y.y, y.z, 0.0], [z.x, z.y, z.z, 0.0], [0.0, 0.0, 0.0, 1.0]];
+        Transform { matrix: matrix, inverse: inverse }
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Quaternion;
+    fn mul(self, other: Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w * other.w - self.v.dot(other.v),
+            v: other.v * self.w + self.v * other.w + self.v.cross(other.v)
+        }
+    }
+}
+
+// Where something is at one moment: scaled, then rotated, then translated.
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe {
+    pub time: f64,
+    pub translation: Vec3d,
+    pub rotation: Quaternion,
+    pub scale: Vec3d
+}
+
+impl Keyframe {
+    pub fn new(time: f64) -> Keyframe {
+        Keyframe { time: time, translation: Vec3d::zero(), rotation: Quaternion::identity(), scale: Vec3d::one() }
+    }
+
+    pub fn transform(&self) -> Transform {
+        Transform::translate(self.translation) * self.rotation.to_transform() * Transform::scale(self.scale)
+    }
+}
+
+// A transform that changes over time, interpolated between keyframes: linearly for translation
+// and scale, and with slerp for rotation. Before the first keyframe and after the last it holds
+// still.
+#[derive(Debug, Clone)]
+pub struct AnimatedTransform {
+    keyframes: Vec<Keyframe>
+}
+
+impl AnimatedTransform {
+    // Panics if there are no keyframes, or any are at a time that isn't finite.
+    pub fn new(mut keyframes: Vec<Keyframe>) -> AnimatedTransform {
+        assert!(!keyframes.is_empty(), "An animated transform needs at least one keyframe");
+        assert!(keyframes.iter().all(|k| k.time.is_finite()), "Keyframe times must be finite");
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        AnimatedTransform { keyframes: keyframes }
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    pub fn at(&self, time: f64) -> Transform {
+        let next = self.keyframes.iter().position(|k| k.time > time).unwrap_or(self.keyframes.len());
+        if next == 0 { return self.keyframes[0].transform(); }
+        if next == self.keyframes.len() { return self.keyframes[next - 1].transform(); }
+        let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        Keyframe {
+            time: time,
+            translation: a.translation * (1.0 - t) + b.translation * t,
+            rotation: a.rotation.slerp(b.rotation, t),
+            scale: a.scale * (1.0 - t) + b.scale * t
+        }.transform()
+    }
+}
+
 pub trait F64Rng {
     fn next(&mut self) -> f64;
 }
@@ -159,3 +496,50 @@ impl F64Rng for XorShiftRng {
         return self.gen::<f64>();
     }
 }
+
+#[test]
+fn transforms_invert() {
+    let t = Transform::translate(Vec3d::new(1.0, 2.0, 3.0)) * Transform::rotate(Vec3d::new(1.0, 1.0, 0.0), 0.7)
+        * Transform::scale(Vec3d::new(2.0, 0.5, 3.0));
+    let p = Vec3d::new(0.3, -1.2, 4.0);
+    assert!((t.inverse().point(t.point(p)) - p).length() < 1e-12);
+    // Inverting the matrix directly agrees with composing the inverses.
+    let general = Transform::new(t.matrix()).unwrap();
+    assert!((general.inverse().point(p) - t.inverse().point(p)).length() < 1e-12);
+    assert!(Transform::new([[1.0, 2.0, 0.0, 0.0], [2.0, 4.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]]).is_none());
+    assert!(Transform::new([[f64::NAN, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]]).is_none());
+    // Normals stay perpendicular to transformed tangents, and volumes scale by the determinant.
+    let (tangent, normal) = (Vec3d::new(1.0, -1.0, 0.5), Vec3d::new(1.0, 1.0, 0.0));
+    assert!(t.vector(tangent).dot(t.normal(normal)).abs() < 1e-12);
+    assert!((t.determinant() - 3.0).abs() < 1e-12);
+    // A quarter turn about z takes x to y.
+    let quarter = Transform::rotate(Vec3d::new(0.0, 0.0, 1.0), ::std::f64::consts::PI / 2.0);
+    assert!((quarter.vector(Vec3d::new(1.0, 0.0, 0.0)) - Vec3d::new(0.0, 1.0, 0.0)).length() < 1e-12);
+}
+
+#[test]
+fn animated_transforms() {
+    use std::f64::consts::PI;
+    let axis = Vec3d::new(1.0, 2.0, -1.0);
+    let p = Vec3d::new(0.3, -1.2, 4.0);
+    // Quaternions rotate the same way as matrices, and can be recovered from a basis.
+    let q = Quaternion::from_axis_angle(axis, 0.8);
+    assert!((q.rotate(p) - Transform::rotate(axis, 0.8).point(p)).length() < 1e-12);
+    let basis = Quaternion::from_basis(q.rotate(Vec3d::new(1.0, 0.0, 0.0)), q.rotate(Vec3d::new(0.0, 1.0, 0.0)),
+                                       q.rotate(Vec3d::new(0.0, 0.0, 1.0)));
+    assert!((basis.dot(q).abs() - 1.0).abs() < 1e-12);
+    assert!(((q * q).rotate(p) - q.rotate(q.rotate(p))).length() < 1e-12);
+
+    let mut end = Keyframe::new(1.0);
+    end.translation = Vec3d::new(2.0, 0.0, 0.0);
+    end.rotation = Quaternion::from_axis_angle(Vec3d::new(0.0, 0.0, 1.0), PI / 2.0);
+    let animation = AnimatedTransform::new(vec![end, Keyframe::new(0.0)]);
+    // Halfway, it's turned by half the angle and moved half the distance.
+    let x = Vec3d::new(1.0, 0.0, 0.0);
+    let halfway = Vec3d::new(1.0 + (PI / 4.0).cos(), (PI / 4.0).sin(), 0.0);
+    assert!((animation.at(0.5).point(x) - halfway).length() < 1e-12);
+    assert!((animation.at(0.5).inverse().point(halfway) - x).length() < 1e-12);
+    // Outside the keyframes it holds still.
+    assert!((animation.at(-1.0).point(x) - x).length() < 1e-12);
+    assert!((animation.at(2.0).point(x) - Vec3d::new(2.0, 1.0, 0.0)).length() < 1e-12);
+}
@@ -0,0 +1,248 @@
+// Participating media: stuff like fog and smoke that fills space, absorbing and scattering light
+// all the way through rather than only at surfaces.
+use bsdf::{Bsdf, Interior};
+use math::{Onb, Vec3d, F64Rng};
+
+use std::f64::consts::PI;
+
+// The Henyey-Greenstein phase function. `g` is the average cosine of the angle light is turned
+// through: positive values scatter it onwards, negative ones back the way it came, and zero
+// equally in all directions.
+#[derive(Debug, Clone, Copy)]
+pub struct HenyeyGreenstein {
+    pub g: f64
+}
+
+impl HenyeyGreenstein {
+    // The density of light turning through an angle with cosine `cos`.
+    pub fn eval(&self, cos: f64) -> f64 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+
+    // Picks the cosine of the angle to turn through, in proportion to eval.
+    pub fn sample_cos(&self, u: f64) -> f64 {
+        let g = self.g;
+        if g.abs() < 1e-3 { return 1.0 - 2.0 * u; }
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+        ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    }
+}
+
+// A homogeneous medium. Light travelling through it is absorbed and scattered at the given
+// rates per unit distance in each channel, and scatters according to the phase function.
+//
+// Distances and transmittance are estimated by tracking: tentative collisions are placed at the
+// rate of the densest channel, and those that are really just empty space in other channels
+// ("null" collisions) are weighted for. That's unnecessary for a single homogeneous medium, but
+// it keeps coloured media unbiased without any special cases.
+#[derive(Debug, Clone, Copy)]
+pub struct Medium {
+    absorption: Vec3d,
+    scattering: Vec3d,
+    phase: HenyeyGreenstein
+}
+
+// Where a ray travelling through a medium was scattered, if it was before reaching the end of its
+// segment, and the weight to apply to its throughput for getting there.
+#[derive(Debug, Clone, Copy)]
+pub struct MediumSample {
+    pub distance: Option<f64>,
+    pub weight: Vec3d
+}
+
+impl Medium {
+    pub fn new(absorption: Vec3d, scattering: Vec3d, g: f64) -> Medium {
+        Medium { absorption: absorption, scattering: scattering, phase: HenyeyGreenstein { g: g } }
+    }
+
+    pub fn extinction(&self) -> Vec3d {
+        self.absorption + self.scattering
+    }
+
+    // The phase function for light scattered towards wo from wi, both pointing away from the
+    // point of scattering as for BSDFs.
+    pub fn phase(&self, wo: Vec3d, wi: Vec3d) -> f64 {
+        self.phase.eval(-wo.dot(wi))
+    }
+
+    // Picks a direction to continue in, by importance sampling the phase function. Returns it
+    // with its pdf, which is also the value of the phase function.
+    pub fn sample_phase(&self, wo: Vec3d, rng: &mut F64Rng) -> (Vec3d, f64) {
+        let cos = self.phase.sample_cos(rng.next());
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.next();
+        let wi = Onb::from_w(wo.neg()).to_world(Vec3d::new(sin * phi.cos(), sin * phi.sin(), cos)).normalized();
+        (wi, self.phase.eval(cos))
+    }
+
+    // Delta tracking, in its spectral form: picks where a ray gets scattered, if it does before
+    // `max_distance`. A ray that's absorbed comes back with a weight of zero. Collisions are
+    // treated as absorption, scattering or null with probabilities in proportion to how much
+    // each contributes to the channels still carrying any weight, so channels the medium doesn't
+    // interact with at all pass straight through even when there's no end to the segment.
+    pub fn sample_distance(&self, max_distance: f64, rng: &mut F64Rng) -> MediumSample {
+        let majorant = self.extinction().max_component();
+        let mut weight = Vec3d::one();
+        if majorant <= 0.0 { return MediumSample { distance: None, weight: weight }; }
+        let null = Vec3d::one() * majorant - self.extinction();
+        let mut distance = 0.0;
+        loop {
+            distance -= (1.0 - rng.next()).ln() / majorant;
+            if distance >= max_distance { return MediumSample { distance: None, weight: weight }; }
+            let absorb = (self.absorption * weight).max_component();
+            let scatter = (self.scattering * weight).max_component();
+            let pass = (null * weight).max_component();
+            if absorb + scatter <= 0.0 { return MediumSample { distance: None, weight: weight }; }
+            let u = rng.next() * (absorb + scatter + pass);
+            if u < absorb {
+                return MediumSample { distance: None, weight: Vec3d::zero() };
+            } else if u < absorb + scatter {
+                weight = weight * self.scattering * ((absorb + scatter + pass) / (scatter * majorant));
+                return MediumSample { distance: Some(distance), weight: weight };
+            }
+            weight = weight * null * ((absorb + scatter + pass) / (pass * majorant));
+        }
+    }
+
+    // Ratio tracking: an unbiased estimate of how much light gets `distance` through the
+    // medium, from the product of the chances of each tentative collision being null.
+    pub fn transmittance(&self, distance: f64, rng: &mut F64Rng) -> Vec3d {
+        let extinction = self.extinction();
+        let majorant = extinction.max_component();
+        if distance.is_infinite() {
+            // Nothing gets through an endless medium, other than in channels it doesn't touch.
+            let through = |sigma: f64| if sigma > 0.0 { 0.0 } else { 1.0 };
+            return Vec3d::new(through(extinction.x), through(extinction.y), through(extinction.z));
+        }
+        let mut transmittance = Vec3d::one();
+        if majorant <= 0.0 { return transmittance; }
+        let null = (Vec3d::one() * majorant - extinction) / majorant;
+        let mut travelled = 0.0;
+        loop {
+            travelled -= (1.0 - rng.next()).ln() / majorant;
+            if travelled >= distance { return transmittance; }
+            transmittance = transmittance * null;
+            if transmittance.max_component() <= 0.0 { return transmittance; }
+        }
+    }
+}
+
+// The media a ray is currently inside, innermost last, starting from the one filling the whole
+// scene if there is one. Each is identified by the material of its surface, so that leaving one
+// removes the right entry even if they overlap.
+#[derive(Clone)]
+pub struct MediumStack {
+    outside: Option<Medium>,
+    media: Vec<(usize, Interior)>
+}
+
+impl MediumStack {
+    pub fn new(outside: Option<Medium>) -> MediumStack {
+        MediumStack { outside: outside, media: Vec::new() }
+    }
+    fn key(material: &Bsdf) -> usize {
+        material as *const Bsdf as *const u8 as usize
+    }
+    // The index of refraction of the medium we're in; outside everything is a vacuum.
+    pub fn ior(&self) -> f64 {
+        self.media.last().map_or(1.0, |m| m.1.ior)
+    }
+    // Beer-Lambert attenuation over `distance` by whatever the interior we're in absorbs, apart
+    // from any scattering medium filling it.
+    pub fn attenuation(&self, distance: f64) -> Vec3d {
+        let absorption = self.media.last().map_or(Vec3d::zero(), |m| m.1.absorption);
+        let channel = |a: f64| if a > 0.0 { (-a * distance).exp() } else { 1.0 };
+        Vec3d::new(channel(absorption.x), channel(absorption.y), channel(absorption.z))
+    }
+    // Whatever scatters light in the medium we're in.
+    pub fn medium(&self) -> Option<Medium> {
+        match self.media.last() {
+            Some(m) => m.1.medium,
+            None => self.outside
+        }
+    }
+    pub fn entering(&self, material: &Bsdf, interior: Interior) -> MediumStack {
+        let mut media = self.clone();
+        media.media.push((MediumStack::key(material), interior));
+        media
+    }
+    pub fn leaving(&self, material: &Bsdf) -> MediumStack {
+        let mut media = self.clone();
+        let key = MediumStack::key(material);
+        if let Some(index) = media.media.iter().rposition(|m| m.0 == key) {
+            media.media.remove(index);
+        }
+        media
+    }
+    // The media after passing through the surface of `material`, into it if `entering`.
+    pub fn crossing(&self, material: &Bsdf, entering: bool) -> MediumStack {
+        match material.interior() {
+            Some(interior) if entering => self.entering(material, interior),
+            Some(_) => self.leaving(material),
+            None => self.clone()
+        }
+    }
+    // An estimate of how much light gets `distance` through the medium we're in.
+    pub fn transmittance(&self, distance: f64, rng: &mut F64Rng) -> Vec3d {
+        let attenuation = self.attenuation(distance);
+        self.medium().map_or(attenuation, |medium| attenuation * medium.transmittance(distance, rng))
+    }
+}
+
+#[test]
+fn henyey_greenstein_sampling() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    for &g in [-0.7, 0.0, 0.4, 0.8].iter() {
+        let medium = Medium::new(Vec3d::zero(), Vec3d::one(), g);
+        let wo = Vec3d::new(0.3, -0.5, 0.8).normalized();
+        let (samples, mut total, mut mean_cos) = (20000, 0.0, 0.0);
+        for _ in 0..samples {
+            let (wi, pdf) = medium.sample_phase(wo, &mut rng);
+            assert!((medium.phase(wo, wi) - pdf).abs() < 1e-9 * pdf.max(1.0));
+            mean_cos += -wo.dot(wi);
+            // Integrate the phase function over the sphere with uniform directions.
+            let z = 1.0 - 2.0 * rng.next();
+            let r = (1.0 - z * z).sqrt();
+            let phi = 2.0 * PI * rng.next();
+            total += medium.phase(wo, Vec3d::new(r * phi.cos(), r * phi.sin(), z)) * 4.0 * PI;
+        }
+        // It's normalized, and g is the average cosine.
+        assert!((total / samples as f64 - 1.0).abs() < 0.05, "g = {} integrates to {}", g, total / samples as f64);
+        assert!((mean_cos / samples as f64 - g).abs() < 0.02);
+    }
+}
+
+#[test]
+fn tracking_matches_beers_law() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    // Coloured, with one channel it doesn't interact with at all.
+    let medium = Medium::new(Vec3d::new(0.3, 0.1, 0.0), Vec3d::new(0.2, 0.4, 0.0), 0.5);
+    let distance = 2.0;
+    let expected = Vec3d::new((-0.5f64 * distance).exp(), (-0.5f64 * distance).exp(), 1.0);
+    let samples = 50000;
+    let (mut ratio, mut passed, mut scattered) = (Vec3d::zero(), Vec3d::zero(), Vec3d::zero());
+    for _ in 0..samples {
+        ratio = ratio + medium.transmittance(distance, &mut rng);
+        let sample = medium.sample_distance(distance, &mut rng);
+        match sample.distance {
+            Some(_) => scattered = scattered + sample.weight,
+            None => passed = passed + sample.weight
+        }
+    }
+    let n = samples as f64;
+    for &(estimate, truth) in [(ratio.x / n, expected.x), (ratio.z / n, expected.z), (passed.x / n, expected.x),
+                               (passed.y / n, expected.y), (passed.z / n, expected.z)].iter() {
+        assert!((estimate - truth).abs() < 0.01, "{} vs {}", estimate, truth);
+    }
+    // Of what doesn't get through, the scattered share is the albedo.
+    let albedo = |i: f64, s: f64| s / (1.0 - i);
+    assert!((albedo(expected.x, scattered.x / n) - 0.4).abs() < 0.02);
+    assert!((albedo(expected.y, scattered.y / n) - 0.8).abs() < 0.02);
+    // Without end, only the untouched channel escapes.
+    let endless = medium.sample_distance(f64::INFINITY, &mut rng);
+    assert!(endless.distance.is_some() || (endless.weight.x == 0.0 && endless.weight.y == 0.0));
+}
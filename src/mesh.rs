@@ -0,0 +1,391 @@
+use bvh::{Aabb, Bvh};
+use geometry::Ray;
+use material::Material;
+use renderable::{Hit, Renderable};
+use math::{Onb, Vec3d, F64Rng};
+use sampling::Distribution1D;
+use texture::{constant_texture, NormalMap, Texture};
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+const EPSILON: f64 = 0.0001;
+
+// Möller–Trumbore ray/triangle intersection. Returns the distance along the ray and the
+// barycentric coordinates (b1, b2) of the hit relative to v1 and v2 respectively.
+fn intersect_triangle(ray: &Ray, v0: Vec3d, v1: Vec3d, v2: Vec3d) -> Option<(f64, f64, f64)> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let p = ray.direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    // Scale the parallel test by the edge lengths so tiny and huge triangles behave the same.
+    if determinant.abs() <= 1e-12 * edge1.length() * edge2.length() { return None; }
+    let inv_det = 1.0 / determinant;
+    let s = ray.origin - v0;
+    let b1 = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&b1) { return None; }
+    let q = s.cross(edge1);
+    let b2 = ray.direction.dot(q) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 { return None; }
+    let t = edge2.dot(q) * inv_det;
+    if t > EPSILON { Some((t, b1, b2)) } else { None }
+}
+
+fn interpolate(values: [Vec3d; 3], b1: f64, b2: f64) -> Vec3d {
+    values[0] * (1.0 - b1 - b2) + values[1] * b1 + values[2] * b2
+}
+
+// The face normal flipped onto the same side as the (interpolated) shading normal.
+fn facing(face: Vec3d, normal: Vec3d) -> Vec3d {
+    if face.dot(normal) < 0.0 { face.neg() } else { face }
+}
+
+// Picks a uniformly distributed point on the triangle and returns what Renderable::random_emission
+// expects.
+fn triangle_emission(vertices: [Vec3d; 3], emission: Vec3d, from: Vec3d, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64) {
+    let (eps1, eps2) = (rng.next(), rng.next());
+    let su = eps1.sqrt();
+    let point = vertices[0] * (1.0 - su) + vertices[1] * (su * (1.0 - eps2)) + vertices[2] * (su * eps2);
+    let cross = (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]);
+    let area = 0.5 * cross.length();
+    let to_light = point - from;
+    let dist_squared = to_light.length_squared();
+    let l = to_light.normalized();
+    let cos_light = cross.normalized().dot(l).abs();
+    if dist_squared <= 0.0 || cos_light <= 0.0 { return (l, Vec3d::zero(), 0.0); }
+    // Converting the uniform area density 1 / A to solid angle: r^2 / (A cos(theta_light)).
+    (l, emission, dist_squared / (area * cos_light))
+}
+
+fn triangle_emission_pdf(vertices: [Vec3d; 3], from: Vec3d, direction: Vec3d) -> f64 {
+    match intersect_triangle(&Ray::new(from, direction), vertices[0], vertices[1], vertices[2]) {
+        None => 0.0,
+        Some((dist, _, _)) => {
+            let cross = (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]);
+            let cos_light = cross.normalized().dot(direction).abs();
+            if cos_light <= 0.0 { 0.0 } else { dist * dist / (0.5 * cross.length() * cos_light) }
+        }
+    }
+}
+
+pub struct Triangle {
+    material: Material,
+    vertices: [Vec3d; 3],
+    normals: Option<[Vec3d; 3]>,
+    emission: Vec3d,
+    colour: Arc<Texture>,
+    normal_map: Option<NormalMap>,
+    emissive: bool,
+}
+
+impl Triangle {
+    pub fn new(material: Material, vertices: [Vec3d; 3], emission: Vec3d, colour: Vec3d) -> Triangle {
+        Triangle {
+            material: material,
+            vertices: vertices,
+            normals: None,
+            emission: emission,
+            colour: constant_texture(colour),
+            normal_map: None,
+            emissive: emission.max_component() > 0.0
+        }
+    }
+    pub fn with_normals(material: Material, vertices: [Vec3d; 3], normals: [Vec3d; 3],
+                        emission: Vec3d, colour: Vec3d) -> Triangle {
+        Triangle {
+            normals: Some([normals[0].normalized(), normals[1].normalized(), normals[2].normalized()]),
+            ..Triangle::new(material, vertices, emission, colour)
+        }
+    }
+    pub fn with_texture(self, texture: Arc<Texture>) -> Triangle {
+        Triangle { colour: texture, ..self }
+    }
+    pub fn with_normal_map(self, map: NormalMap) -> Triangle {
+        Triangle { normal_map: Some(map), ..self }
+    }
+}
+
+impl Renderable for Triangle {
+    fn get_hit(&self, ray: &Ray, dist: f64) -> Hit<'_> {
+        let pos = ray.origin + ray.direction * dist;
+        let v = self.vertices;
+        // The barycentric coordinates double as texture coordinates.
+        let (b1, b2) = intersect_triangle(ray, v[0], v[1], v[2]).map_or((0.0, 0.0), |(_, b1, b2)| (b1, b2));
+        let face = (v[1] - v[0]).cross(v[2] - v[0]).normalized();
+        let normal = match self.normals {
+            Some(normals) => interpolate(normals, b1, b2).normalized(),
+            None => face
+        };
+        Hit {
+            pos: pos,
+            normal: normal,
+            geometric_normal: facing(face, normal),
+            material: &*self.material,
+            colour: self.colour.value((b1, b2), pos),
+            emission: self.emission,
+            uv: (b1, b2),
+            dpdu: v[1] - v[0],
+            dpdv: v[2] - v[0],
+            object: self
+        }.with_normal_map(self.normal_map.as_ref())
+    }
+    fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(&self.vertices)
+    }
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        let v = self.vertices;
+        intersect_triangle(ray, v[0], v[1], v[2]).map(|(t, _, _)| t)
+    }
+    fn is_emissive(&self) -> bool { self.emissive }
+    fn random_emission(&self, from: Vec3d, _time: f64, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64) {
+        triangle_emission(self.vertices, self.emission, from, rng)
+    }
+    fn emission_pdf(&self, from: Vec3d, direction: Vec3d, _time: f64) -> f64 {
+        triangle_emission_pdf(self.vertices, from, direction)
+    }
+    fn power(&self) -> f64 {
+        // Triangles emit from both sides.
+        let v = self.vertices;
+        (v[1] - v[0]).cross(v[2] - v[0]).length() * PI * self.emission.luminance()
+    }
+    fn identity(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+// An indexed triangle mesh sharing a single material. If normals or texture coordinates are
+// given there must be one per vertex, and they are interpolated across each face; without
+// texture coordinates, each face has its own barycentric ones. Like single triangles, emissive
+// meshes light both sides of every face.
+pub struct TriangleMesh {
+    material: Material,
+    vertices: Vec<Vec3d>,
+    normals: Option<Vec<Vec3d>>,
+    uvs: Option<Vec<(f64, f64)>>,
+    triangles: Vec<[usize; 3]>,
+    bvh: Bvh,
+    emission: Vec3d,
+    colour: Arc<Texture>,
+    normal_map: Option<NormalMap>,
+    // Picks triangles in proportion to their area, so points are uniform over the whole mesh.
+    // Only built for emissive meshes.
+    areas: Option<Distribution1D>,
+    area: f64
+}
+
+impl TriangleMesh {
+    pub fn new(material: Material, vertices: Vec<Vec3d>, normals: Option<Vec<Vec3d>>,
+               triangles: Vec<[usize; 3]>, emission: Vec3d, colour: Vec3d) -> TriangleMesh {
+        if let Some(ref normals) = normals {
+            assert_eq!(normals.len(), vertices.len(), "Need exactly one normal per vertex");
+        }
+        for tri in triangles.iter() {
+            assert!(tri.iter().all(|&i| i < vertices.len()), "Triangle index out of range");
+        }
+        let bounds: Vec<Aabb> = triangles.iter()
+            .map(|tri| Aabb::from_points(&[vertices[tri[0]], vertices[tri[1]], vertices[tri[2]]]))
+            .collect();
+        let areas: Vec<f64> = triangles.iter().map(|tri| {
+            0.5 * (vertices[tri[1]] - vertices[tri[0]]).cross(vertices[tri[2]] - vertices[tri[0]]).length()
+        }).collect();
+        let area = areas.iter().sum();
+        let emissive = emission.max_component() > 0.0 && area > 0.0;
+        TriangleMesh {
+            material: material,
+            normals: normals.map(|ns| ns.into_iter().map(|n| n.normalized()).collect()),
+            bvh: Bvh::new(&bounds),
+            uvs: None,
+            vertices: vertices,
+            triangles: triangles,
+            emission: emission,
+            colour: constant_texture(colour),
+            normal_map: None,
+            areas: if emissive { Some(Distribution1D::new(areas)) } else { None },
+            area: area
+        }
+    }
+
+    pub fn with_uvs(self, uvs: Vec<(f64, f64)>) -> TriangleMesh {
+        assert_eq!(uvs.len(), self.vertices.len(), "Need exactly one texture coordinate per vertex");
+        TriangleMesh { uvs: Some(uvs), ..self }
+    }
+
+    pub fn with_texture(self, texture: Arc<Texture>) -> TriangleMesh {
+        TriangleMesh { colour: texture, ..self }
+    }
+    pub fn with_normal_map(self, map: NormalMap) -> TriangleMesh {
+        TriangleMesh { normal_map: Some(map), ..self }
+    }
+
+    pub fn num_triangles(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn triangle_vertices(&self, index: usize) -> [Vec3d; 3] {
+        let tri = self.triangles[index];
+        [self.vertices[tri[0]], self.vertices[tri[1]], self.vertices[tri[2]]]
+    }
+
+    // Finds the closest triangle hit by the ray: (triangle index, distance, b1, b2).
+    fn closest_hit(&self, ray: &Ray) -> Option<(usize, f64, f64, f64)> {
+        let (index, _) = self.bvh.intersect(ray, |index| {
+            let v = self.triangle_vertices(index);
+            intersect_triangle(ray, v[0], v[1], v[2]).map(|(t, _, _)| t)
+        })?;
+        // Recompute the winner to recover its barycentrics.
+        let v = self.triangle_vertices(index);
+        intersect_triangle(ray, v[0], v[1], v[2]).map(|(t, b1, b2)| (index, t, b1, b2))
+    }
+}
+
+impl Renderable for TriangleMesh {
+    fn get_hit(&self, ray: &Ray, dist: f64) -> Hit<'_> {
+        let pos = ray.origin + ray.direction * dist;
+        let (index, _, b1, b2) = self.closest_hit(ray).expect("get_hit called on a missed ray");
+        let v = self.triangle_vertices(index);
+        let tri = self.triangles[index];
+        let face = (v[1] - v[0]).cross(v[2] - v[0]).normalized();
+        let normal = match self.normals {
+            Some(ref normals) => interpolate([normals[tri[0]], normals[tri[1]], normals[tri[2]]], b1, b2).normalized(),
+            None => face
+        };
+        let (uv, dpdu, dpdv) = match self.uvs {
+            Some(ref uvs) => {
+                let (t0, t1, t2) = (uvs[tri[0]], uvs[tri[1]], uvs[tri[2]]);
+                let b0 = 1.0 - b1 - b2;
+                let uv = (t0.0 * b0 + t1.0 * b1 + t2.0 * b2, t0.1 * b0 + t1.1 * b1 + t2.1 * b2);
+                // Solve for the derivatives from the position and uv differences along two edges.
+                let (du02, dv02, du12, dv12) = (t0.0 - t2.0, t0.1 - t2.1, t1.0 - t2.0, t1.1 - t2.1);
+                let (dp02, dp12) = (v[0] - v[2], v[1] - v[2]);
+                let determinant = du02 * dv12 - dv02 * du12;
+                if determinant.abs() > 1e-12 {
+                    let inv = 1.0 / determinant;
+                    (uv, (dp02 * dv12 - dp12 * dv02) * inv, (dp12 * du02 - dp02 * du12) * inv)
+                } else {
+                    // Degenerate texture coordinates, so any tangents will do.
+                    let frame = Onb::from_w(face);
+                    (uv, frame.u, frame.v)
+                }
+            },
+            None => ((b1, b2), v[1] - v[0], v[2] - v[0])
+        };
+        Hit {
+            pos: pos,
+            normal: normal,
+            geometric_normal: facing(face, normal),
+            material: &*self.material,
+            colour: self.colour.value(uv, pos),
+            emission: self.emission,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            object: self
+        }.with_normal_map(self.normal_map.as_ref())
+    }
+    fn bounding_box(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        self.closest_hit(ray).map(|(_, t, _, _)| t)
+    }
+    fn is_emissive(&self) -> bool { self.areas.is_some() }
+    fn random_emission(&self, from: Vec3d, _time: f64, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64) {
+        let areas = match self.areas {
+            Some(ref areas) => areas,
+            None => return (Vec3d::zero(), Vec3d::zero(), 0.0)
+        };
+        let (index, probability) = areas.sample_discrete(rng.next());
+        let (l, emission, pdf) = triangle_emission(self.triangle_vertices(index), self.emission, from, rng);
+        if pdf <= 0.0 { return (l, emission, 0.0); }
+        // The scene only checks that the first thing the shadow ray hits is this mesh, so make
+        // sure it's the point we picked, not some other part of the mesh in front of it.
+        match self.closest_hit(&Ray::new(from, l)) {
+            Some((hit, _, _, _)) if hit == index => (l, emission, pdf * probability),
+            _ => (l, Vec3d::zero(), 0.0)
+        }
+    }
+    fn emission_pdf(&self, from: Vec3d, direction: Vec3d, _time: f64) -> f64 {
+        if self.areas.is_none() { return 0.0; }
+        // Uniform over the mesh's whole area, converted to solid angle at the nearest point.
+        match self.closest_hit(&Ray::new(from, direction)) {
+            None => 0.0,
+            Some((index, dist, _, _)) => {
+                let v = self.triangle_vertices(index);
+                let cos_light = (v[1] - v[0]).cross(v[2] - v[0]).normalized().dot(direction).abs();
+                if cos_light <= 0.0 { 0.0 } else { dist * dist / (self.area * cos_light) }
+            }
+        }
+    }
+    fn power(&self) -> f64 {
+        if self.is_emissive() { 2.0 * self.area * PI * self.emission.luminance() } else { 0.0 }
+    }
+    fn identity(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+#[test]
+fn triangle_intersection() {
+    use material::Lambertian;
+    use std::sync::Arc;
+    let triangle = Triangle::new(
+        Arc::new(Lambertian),
+        [Vec3d::new(-1.0, -1.0, 5.0), Vec3d::new(1.0, -1.0, 5.0), Vec3d::new(0.0, 1.0, 5.0)],
+        Vec3d::zero(),
+        Vec3d::zero());
+    let ray = Ray::new(Vec3d::zero(), Vec3d::new(0.0, 0.0, 1.0));
+    match triangle.intersect(&ray) {
+        Some(x) => assert_eq!(x, 5.0),
+        None => panic!("unexpected")
+    }
+    let hit = triangle.get_hit(&ray, 5.0);
+    assert_eq!(hit.normal.z.abs(), 1.0);
+    let ray = Ray::new(Vec3d::new(2.0, 0.0, 0.0), Vec3d::new(0.0, 0.0, 1.0));
+    assert!(triangle.intersect(&ray).is_none());
+}
+
+#[test]
+fn mesh_interpolates_normals() {
+    use material::Lambertian;
+    use std::sync::Arc;
+    let vertices = vec![Vec3d::new(0.0, 0.0, 1.0), Vec3d::new(1.0, 0.0, 1.0),
+                        Vec3d::new(1.0, 1.0, 1.0), Vec3d::new(0.0, 1.0, 1.0)];
+    let normals = vec![Vec3d::new(-1.0, 0.0, -1.0), Vec3d::new(1.0, 0.0, -1.0),
+                       Vec3d::new(1.0, 0.0, -1.0), Vec3d::new(-1.0, 0.0, -1.0)];
+    let mesh = TriangleMesh::new(Arc::new(Lambertian), vertices, Some(normals),
+                                 vec![[0, 1, 2], [0, 2, 3]], Vec3d::zero(), Vec3d::zero());
+    let ray = Ray::new(Vec3d::new(0.5, 0.75, 0.0), Vec3d::new(0.0, 0.0, 1.0));
+    let dist = mesh.intersect(&ray).expect("should hit the second triangle");
+    assert_eq!(dist, 1.0);
+    let hit = mesh.get_hit(&ray, dist);
+    // Halfway across in x, so the interpolated normal should face straight back down the ray.
+    assert!(hit.normal.x.abs() < 1e-9);
+    assert!((hit.normal.z + 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn emissive_mesh_sampling() {
+    use material::Lambertian;
+    use rand::{SeedableRng, XorShiftRng};
+    use std::sync::Arc;
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    // A small triangle at z = 4 partly hiding a big one, four times its area, at z = 5.
+    let vertices = vec![Vec3d::new(-1.0, -1.0, 5.0), Vec3d::new(5.0, -1.0, 5.0), Vec3d::new(-1.0, 5.0, 5.0),
+                        Vec3d::new(-1.0, -1.0, 4.0), Vec3d::new(2.0, -1.0, 4.0), Vec3d::new(-1.0, 2.0, 4.0)];
+    let mesh = TriangleMesh::new(Arc::new(Lambertian), vertices, None, vec![[0, 1, 2], [3, 4, 5]],
+                                 Vec3d::one(), Vec3d::zero());
+    assert!(mesh.is_emissive());
+    let from = Vec3d::zero();
+    let (mut visible, n) = (0, 4000);
+    for _ in 0..n {
+        let (direction, emission, pdf) = mesh.random_emission(from, 0.0, &mut rng);
+        if pdf == 0.0 { continue; }
+        visible += 1;
+        assert_eq!(emission.x, 1.0);
+        assert!((mesh.emission_pdf(from, direction, 0.0) - pdf).abs() < 1e-9 * pdf);
+    }
+    // Seen from the origin, the small triangle's shadow covers a 3.25 x 3.25 corner of the big
+    // one, out of 22.5 square units of mesh.
+    let hidden = 1.0 - visible as f64 / n as f64;
+    assert!((hidden - 3.25 * 3.25 / 2.0 / 22.5).abs() < 0.03, "{} hidden", hidden);
+}
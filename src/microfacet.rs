@@ -0,0 +1,205 @@
+// Microfacet models for rough conductors and dielectrics. Vectors passed to the distribution are
+// in a local shading frame with the macro surface normal along +z.
+use math::{Vec3d, F64Rng};
+
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MicrofacetType {
+    Ggx,
+    Beckmann
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MicrofacetDistribution {
+    pub kind: MicrofacetType,
+    pub alpha: f64
+}
+
+impl MicrofacetDistribution {
+    // Roughness runs from 0 (a perfect mirror) to 1; it's remapped to alpha = roughness^2,
+    // which gives a more perceptually even response.
+    pub fn new(kind: MicrofacetType, roughness: f64) -> MicrofacetDistribution {
+        MicrofacetDistribution { kind: kind, alpha: (roughness * roughness).max(1e-4) }
+    }
+
+    // The distribution of microfacet normals, D(m).
+    pub fn d(&self, m: Vec3d) -> f64 {
+        if m.z <= 0.0 { return 0.0; }
+        let cos2 = m.z * m.z;
+        let alpha2 = self.alpha * self.alpha;
+        match self.kind {
+            MicrofacetType::Ggx => {
+                let denom = cos2 * (alpha2 - 1.0) + 1.0;
+                alpha2 / (PI * denom * denom)
+            },
+            MicrofacetType::Beckmann => {
+                let tan2 = (1.0 - cos2) / cos2;
+                (-tan2 / alpha2).exp() / (PI * alpha2 * cos2 * cos2)
+            }
+        }
+    }
+
+    // Smith's auxiliary function, used to build the shadowing-masking terms.
+    fn lambda(&self, w: Vec3d) -> f64 {
+        let cos2 = w.z * w.z;
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        if tan2 == 0.0 || !tan2.is_finite() { return if tan2 == 0.0 { 0.0 } else { f64::INFINITY }; }
+        match self.kind {
+            MicrofacetType::Ggx => (-1.0 + (1.0 + self.alpha * self.alpha * tan2).sqrt()) / 2.0,
+            MicrofacetType::Beckmann => {
+                let a = 1.0 / (self.alpha * tan2.sqrt());
+                if a >= 1.6 { 0.0 } else { (1.0 - 1.259 * a + 0.396 * a * a) / (3.535 * a + 2.181 * a * a) }
+            }
+        }
+    }
+
+    pub fn g1(&self, w: Vec3d) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height-correlated masking and shadowing.
+    pub fn g(&self, wo: Vec3d, wi: Vec3d) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Samples a microfacet normal as seen from wo, which must be in the upper hemisphere. For
+    // GGX this samples only normals visible from wo (Heitz 2018); Beckmann falls back to
+    // sampling D(m) cos(m).
+    pub fn sample_normal(&self, wo: Vec3d, rng: &mut F64Rng) -> Vec3d {
+        let (u1, u2) = (rng.next(), rng.next());
+        match self.kind {
+            MicrofacetType::Ggx => {
+                let vh = Vec3d::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalized();
+                let lensq = vh.x * vh.x + vh.y * vh.y;
+                let t1 = if lensq > 0.0 { Vec3d::new(-vh.y, vh.x, 0.0) * (1.0 / lensq.sqrt()) } else { Vec3d::new(1.0, 0.0, 0.0) };
+                let t2 = vh.cross(t1);
+                let r = u1.sqrt();
+                let phi = 2.0 * PI * u2;
+                let p1 = r * phi.cos();
+                let s = 0.5 * (1.0 + vh.z);
+                let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+                let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+                Vec3d::new(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(1e-6)).normalized()
+            },
+            MicrofacetType::Beckmann => {
+                let tan2 = -self.alpha * self.alpha * (1.0 - u1).ln();
+                let cos = 1.0 / (1.0 + tan2).sqrt();
+                let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                let phi = 2.0 * PI * u2;
+                Vec3d::new(sin * phi.cos(), sin * phi.sin(), cos)
+            }
+        }
+    }
+
+    // The density with which sample_normal picks m, given wo.
+    pub fn pdf(&self, wo: Vec3d, m: Vec3d) -> f64 {
+        match self.kind {
+            MicrofacetType::Ggx => {
+                if wo.z <= 0.0 { return 0.0; }
+                self.g1(wo) * wo.dot(m).max(0.0) * self.d(m) / wo.z
+            },
+            MicrofacetType::Beckmann => self.d(m) * m.z.abs()
+        }
+    }
+
+    // The throughput weight f cos / pdf of a sampled direction, ignoring Fresnel.
+    pub fn sample_weight(&self, wo: Vec3d, wi: Vec3d, m: Vec3d) -> f64 {
+        match self.kind {
+            MicrofacetType::Ggx => self.g(wo, wi) / self.g1(wo),
+            MicrofacetType::Beckmann => self.g(wo, wi) * wo.dot(m).abs() / (wo.z.abs() * m.z.abs())
+        }
+    }
+}
+
+// Unpolarised Fresnel reflectance at the boundary of a conductor with complex index of
+// refraction eta + ik, for one wavelength.
+pub fn fresnel_conductor(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2plusb2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let t1 = a2plusb2 + cos2;
+    let a = (0.5 * (a2plusb2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2plusb2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+// Unpolarised Fresnel reflectance going from a medium of index eta_i into one of eta_t.
+// `cos_i` must be positive.
+pub fn fresnel_dielectric(cos_i: f64, eta_i: f64, eta_t: f64) -> f64 {
+    let sin_t = eta_i / eta_t * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    if sin_t >= 1.0 { return 1.0; }
+    let cos_t = (1.0 - sin_t * sin_t).sqrt();
+    let parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+pub fn reflect(wo: Vec3d, m: Vec3d) -> Vec3d {
+    m * (2.0 * wo.dot(m)) - wo
+}
+
+// Refracts wo (pointing away from the surface, on the same side as m) through the microfacet
+// m, with eta = eta_i / eta_t. Returns None on total internal reflection.
+pub fn refract(wo: Vec3d, m: Vec3d, eta: f64) -> Option<Vec3d> {
+    let cos_i = wo.dot(m);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 { return None; }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(wo.neg() * eta + m * (eta * cos_i - cos_t))
+}
+
+// Complex indices of refraction for some common metals, at roughly 650nm, 550nm and 450nm.
+pub struct ConductorPreset {
+    pub name: &'static str,
+    pub eta: Vec3d,
+    pub k: Vec3d
+}
+
+pub const CONDUCTOR_PRESETS: [ConductorPreset; 4] = [
+    ConductorPreset { name: "gold",
+        eta: Vec3d { x: 0.143119, y: 0.374957, z: 1.44248 }, k: Vec3d { x: 3.98316, y: 2.38572, z: 1.60322 } },
+    ConductorPreset { name: "copper",
+        eta: Vec3d { x: 0.200438, y: 0.924033, z: 1.10221 }, k: Vec3d { x: 3.91295, y: 2.45285, z: 2.14219 } },
+    ConductorPreset { name: "aluminium",
+        eta: Vec3d { x: 1.65746, y: 0.880369, z: 0.521229 }, k: Vec3d { x: 9.22387, y: 6.26952, z: 4.837 } },
+    ConductorPreset { name: "silver",
+        eta: Vec3d { x: 0.155265, y: 0.116723, z: 0.138342 }, k: Vec3d { x: 4.82835, y: 3.12225, z: 2.14696 } },
+];
+
+pub fn conductor_preset(name: &str) -> Option<&'static ConductorPreset> {
+    CONDUCTOR_PRESETS.iter().find(|p| p.name == name)
+}
+
+#[test]
+fn microfacet_pdfs_are_normalised() {
+    use rand::{SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let wo = Vec3d::new(0.6, 0.0, 0.8);
+    for &kind in [MicrofacetType::Ggx, MicrofacetType::Beckmann].iter() {
+        let distribution = MicrofacetDistribution::new(kind, 0.5);
+        // Integrate the pdf over the hemisphere with uniformly distributed directions.
+        let n = 50000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let z = rng.next();
+            let phi = 2.0 * PI * rng.next();
+            let r = (1.0 - z * z).sqrt();
+            sum += distribution.pdf(wo, Vec3d::new(r * phi.cos(), r * phi.sin(), z)) * 2.0 * PI;
+        }
+        let integral = sum / n as f64;
+        assert!((integral - 1.0).abs() < 0.05, "{:?} integrates to {}", kind, integral);
+        for _ in 0..100 {
+            assert!(distribution.pdf(wo, distribution.sample_normal(wo, &mut rng)) > 0.0);
+        }
+    }
+    assert!((fresnel_dielectric(1.0, 1.0, 1.5) - 0.04).abs() < 1e-9);
+    assert_eq!(fresnel_dielectric(0.1, 1.5, 1.0), 1.0);
+    // A conductor that doesn't absorb is just a dielectric.
+    assert!((fresnel_conductor(0.7, 1.5, 0.0) - fresnel_dielectric(0.7, 1.0, 1.5)).abs() < 1e-9);
+}
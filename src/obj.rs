@@ -0,0 +1,413 @@
+// Wavefront OBJ and MTL loading. Only the geometry we can render is understood: vertices,
+// normals, texture coordinates and polygonal faces (which are triangulated as fans). Groups,
+// objects and smoothing statements are ignored. Materials can have a diffuse texture (map_Kd),
+// a tangent space normal map (norm) and a bump map (bump or map_Bump, scaled by -bm).
+use error::LoadError;
+use material::{Dielectric, Lambertian, Material, Mirror};
+use math::Vec3d;
+use mesh::TriangleMesh;
+use scene::Scene;
+use texture::{ImageTexture, NormalMap, Texture, WrapMode};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+type Result<T> = ::std::result::Result<T, LoadError>;
+
+// A material as described by an MTL file, mapped onto our own Material.
+#[derive(Clone)]
+pub struct ObjMaterial {
+    pub material: Material,
+    pub colour: Vec3d,
+    pub emission: Vec3d,
+    // Replaces the colour, where the mesh has texture coordinates.
+    pub texture: Option<Arc<Texture>>,
+    // Likewise only used with texture coordinates.
+    pub normal_map: Option<NormalMap>
+}
+
+impl ObjMaterial {
+    // Used for faces before any usemtl statement.
+    fn grey() -> ObjMaterial {
+        ObjMaterial {
+            material: Arc::new(Lambertian),
+            colour: Vec3d::new(0.75, 0.75, 0.75),
+            emission: Vec3d::zero(),
+            texture: None,
+            normal_map: None
+        }
+    }
+}
+
+// Raw MTL parameters, converted to an ObjMaterial once the whole definition has been read.
+struct MtlDefinition {
+    name: String,
+    kd: Vec3d,
+    ks: Vec3d,
+    ke: Vec3d,
+    tf: Option<Vec3d>,
+    ior: f64,
+    dissolve: f64,
+    illum: u32,
+    map_kd: Option<Arc<Texture>>,
+    normal_map: Option<NormalMap>
+}
+
+impl MtlDefinition {
+    fn new(name: &str) -> MtlDefinition {
+        MtlDefinition {
+            name: name.to_string(),
+            kd: Vec3d::new(0.75, 0.75, 0.75),
+            ks: Vec3d::zero(),
+            ke: Vec3d::zero(),
+            tf: None,
+            ior: 1.5,
+            dissolve: 1.0,
+            illum: 2,
+            map_kd: None,
+            normal_map: None
+        }
+    }
+
+    // Illumination models 4, 6, 7 and 9 are the transparent ones; 3, 5 and 8 are mirrors.
+    fn to_material(&self) -> ObjMaterial {
+        let transparent = self.dissolve < 1.0 || [4, 6, 7, 9].contains(&self.illum);
+        let (material, colour) = if transparent {
+            let material: Material = Arc::new(Dielectric::new(self.ior, Vec3d::zero()));
+            (material, self.tf.unwrap_or(Vec3d::new(0.999, 0.999, 0.999)))
+        } else if [3, 5, 8].contains(&self.illum) {
+            (Arc::new(Mirror) as Material, if self.ks.max_component() > 0.0 { self.ks } else { self.kd })
+        } else {
+            (Arc::new(Lambertian) as Material, self.kd)
+        };
+        ObjMaterial {
+            material: material,
+            colour: colour,
+            emission: self.ke,
+            texture: self.map_kd.clone(),
+            normal_map: self.normal_map.clone()
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path).map(BufReader::new)
+        .map_err(|e| LoadError::IoError(path.display().to_string(), e))
+}
+
+fn parse_f64(token: Option<&str>, file: &str, line: usize) -> Result<f64> {
+    match token {
+        None => Err(LoadError::parse(file, line, "Missing number")),
+        Some(token) => match token.parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok(value),
+            _ => Err(LoadError::parse(file, line, format!("Expected a number, got '{}'", token)))
+        }
+    }
+}
+
+fn parse_vec3<'a, I: Iterator<Item=&'a str>>(tokens: &mut I, file: &str, line: usize) -> Result<Vec3d> {
+    let x = parse_f64(tokens.next(), file, line)?;
+    let y = parse_f64(tokens.next(), file, line)?;
+    let z = parse_f64(tokens.next(), file, line)?;
+    Ok(Vec3d::new(x, y, z))
+}
+
+// Converts a 1-based (or negative, relative) OBJ index into a 0-based one.
+fn resolve_index(token: &str, count: usize, file: &str, line: usize) -> Result<usize> {
+    let index: i64 = token.parse().map_err(|_| {
+        LoadError::parse(file, line, format!("Bad index '{}'", token))
+    })?;
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(LoadError::parse(file, line, format!("Index {} out of range", index)));
+    }
+    Ok(resolved as usize)
+}
+
+// Texture maps are looked up relative to `base_dir`.
+pub fn parse_mtl<R: BufRead>(reader: R, file: &str, base_dir: &Path) -> Result<HashMap<String, ObjMaterial>> {
+    let mut materials = HashMap::new();
+    let mut current: Option<MtlDefinition> = None;
+    for (line_no, line) in reader.lines().enumerate() {
+        let line_no = line_no + 1;
+        let line = line.map_err(|e| LoadError::IoError(file.to_string(), e))?;
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            None => continue,
+            Some(k) if k.starts_with('#') => continue,
+            Some(k) => k
+        };
+        if keyword == "newmtl" {
+            let name = tokens.next().ok_or_else(|| LoadError::parse(file, line_no, "Missing material name"))?;
+            if let Some(def) = current.take() {
+                materials.insert(def.name.clone(), def.to_material());
+            }
+            current = Some(MtlDefinition::new(name));
+            continue;
+        }
+        let def = match current {
+            Some(ref mut def) => def,
+            None => return Err(LoadError::parse(file, line_no, format!("'{}' before any newmtl", keyword)))
+        };
+        match keyword {
+            "Kd" => def.kd = parse_vec3(&mut tokens, file, line_no)?,
+            "Ks" => def.ks = parse_vec3(&mut tokens, file, line_no)?,
+            "Ke" => def.ke = parse_vec3(&mut tokens, file, line_no)?,
+            "Tf" => def.tf = Some(parse_vec3(&mut tokens, file, line_no)?),
+            "Ni" => {
+                def.ior = parse_f64(tokens.next(), file, line_no)?;
+                if def.ior <= 0.0 {
+                    return Err(LoadError::parse(file, line_no, "Index of refraction must be positive"));
+                }
+            }
+            "d" => def.dissolve = parse_f64(tokens.next(), file, line_no)?,
+            "Tr" => def.dissolve = 1.0 - parse_f64(tokens.next(), file, line_no)?,
+            "illum" => def.illum = parse_f64(tokens.next(), file, line_no)? as u32,
+            // Any options come before the filename, and apart from -bm we don't support them.
+            "map_Kd" => {
+                let name = tokens.last().ok_or_else(|| LoadError::parse(file, line_no, "Missing texture filename"))?;
+                def.map_kd = Some(Arc::new(ImageTexture::load(base_dir.join(name), WrapMode::Repeat)?));
+            },
+            "norm" => {
+                let name = tokens.last().ok_or_else(|| LoadError::parse(file, line_no, "Missing texture filename"))?;
+                let map = ImageTexture::load_linear(base_dir.join(name), WrapMode::Repeat)?;
+                def.normal_map = Some(NormalMap::Tangent(Arc::new(map)));
+            },
+            "bump" | "map_Bump" => {
+                let tokens: Vec<&str> = tokens.collect();
+                let name = tokens.last().ok_or_else(|| LoadError::parse(file, line_no, "Missing texture filename"))?;
+                let scale = match tokens.iter().position(|&t| t == "-bm") {
+                    Some(i) => parse_f64(tokens.get(i + 1).cloned(), file, line_no)?,
+                    None => 1.0
+                };
+                let map = ImageTexture::load_linear(base_dir.join(name), WrapMode::Repeat)?;
+                def.normal_map = Some(NormalMap::Bump(Arc::new(map), scale));
+            },
+            // Everything else (Ka, Ns, texture maps...) has no equivalent for us yet.
+            _ => {}
+        }
+    }
+    if let Some(def) = current.take() {
+        materials.insert(def.name.clone(), def.to_material());
+    }
+    Ok(materials)
+}
+
+// The position, texture coordinate and normal indices of a face's vertex.
+type VertexKey = (usize, Option<usize>, Option<usize>);
+
+// The triangles of one material, with vertices de-duplicated by their indices.
+struct MeshBuilder {
+    material: ObjMaterial,
+    vertices: Vec<Vec3d>,
+    normals: Vec<Vec3d>,
+    uvs: Vec<(f64, f64)>,
+    all_have_normals: bool,
+    all_have_uvs: bool,
+    triangles: Vec<[usize; 3]>,
+    index_map: HashMap<VertexKey, usize>
+}
+
+impl MeshBuilder {
+    fn new(material: ObjMaterial) -> MeshBuilder {
+        MeshBuilder {
+            material: material,
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            all_have_normals: true,
+            all_have_uvs: true,
+            triangles: Vec::new(),
+            index_map: HashMap::new()
+        }
+    }
+
+    fn vertex(&mut self, key: VertexKey, positions: &[Vec3d], uvs: &[(f64, f64)], normals: &[Vec3d]) -> usize {
+        if let Some(&index) = self.index_map.get(&key) { return index; }
+        let index = self.vertices.len();
+        self.vertices.push(positions[key.0]);
+        match key.1 {
+            Some(t) => self.uvs.push(uvs[t]),
+            None => {
+                self.all_have_uvs = false;
+                self.uvs.push((0.0, 0.0));
+            }
+        }
+        match key.2 {
+            Some(n) => self.normals.push(normals[n]),
+            None => {
+                self.all_have_normals = false;
+                self.normals.push(Vec3d::zero());
+            }
+        }
+        self.index_map.insert(key, index);
+        index
+    }
+
+    fn build(self) -> TriangleMesh {
+        let normals = if self.all_have_normals { Some(self.normals) } else { None };
+        let mesh = TriangleMesh::new(self.material.material, self.vertices, normals, self.triangles,
+                                     self.material.emission, self.material.colour);
+        if !self.all_have_uvs { return mesh; }
+        let mesh = mesh.with_uvs(self.uvs);
+        let mesh = match self.material.texture {
+            Some(texture) => mesh.with_texture(texture),
+            None => mesh
+        };
+        match self.material.normal_map {
+            Some(map) => mesh.with_normal_map(map),
+            None => mesh
+        }
+    }
+}
+
+// Parses OBJ data, returning one mesh per material used. Material libraries are looked up
+// relative to `base_dir`.
+pub fn parse_obj<R: BufRead>(reader: R, file: &str, base_dir: &Path) -> Result<Vec<TriangleMesh>> {
+    let mut positions: Vec<Vec3d> = Vec::new();
+    let mut normals: Vec<Vec3d> = Vec::new();
+    let mut uvs: Vec<(f64, f64)> = Vec::new();
+    let mut materials: HashMap<String, ObjMaterial> = HashMap::new();
+    let mut builders: Vec<MeshBuilder> = vec![MeshBuilder::new(ObjMaterial::grey())];
+    let mut builder_by_name: HashMap<String, usize> = HashMap::new();
+    let mut current = 0;
+    for (line_no, line) in reader.lines().enumerate() {
+        let line_no = line_no + 1;
+        let line = line.map_err(|e| LoadError::IoError(file.to_string(), e))?;
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            None => {}
+            Some(k) if k.starts_with('#') => {}
+            Some("v") => positions.push(parse_vec3(&mut tokens, file, line_no)?),
+            Some("vn") => {
+                let normal = parse_vec3(&mut tokens, file, line_no)?;
+                if normal.length() == 0.0 {
+                    return Err(LoadError::parse(file, line_no, "Normal has zero length"));
+                }
+                normals.push(normal);
+            }
+            Some("vt") => {
+                let u = parse_f64(tokens.next(), file, line_no)?;
+                let v = match tokens.next() {
+                    Some(v) => parse_f64(Some(v), file, line_no)?,
+                    None => 0.0
+                };
+                uvs.push((u, v));
+            },
+            Some("f") => {
+                let mut face: Vec<VertexKey> = Vec::new();
+                for vertex in tokens {
+                    let mut parts = vertex.split('/');
+                    let position = resolve_index(parts.next().unwrap(), positions.len(), file, line_no)?;
+                    let uv = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(resolve_index(t, uvs.len(), file, line_no)?),
+                        _ => None
+                    };
+                    let normal = match parts.next() {
+                        Some(n) if !n.is_empty() => Some(resolve_index(n, normals.len(), file, line_no)?),
+                        _ => None
+                    };
+                    face.push((position, uv, normal));
+                }
+                if face.len() < 3 {
+                    return Err(LoadError::parse(file, line_no, "Face needs at least three vertices"));
+                }
+                let builder = &mut builders[current];
+                let indices: Vec<usize> = face.iter()
+                    .map(|&key| builder.vertex(key, &positions, &uvs, &normals))
+                    .collect();
+                for i in 1..indices.len() - 1 {
+                    builder.triangles.push([indices[0], indices[i], indices[i + 1]]);
+                }
+            },
+            Some("mtllib") => {
+                for name in tokens {
+                    let path = base_dir.join(name);
+                    let reader = open(&path)?;
+                    let library = parse_mtl(reader, &path.display().to_string(), base_dir)?;
+                    materials.extend(library);
+                }
+            },
+            Some("usemtl") => {
+                let name = tokens.next().ok_or_else(|| LoadError::parse(file, line_no, "Missing material name"))?;
+                let material = materials.get(name).cloned().ok_or_else(|| {
+                    LoadError::parse(file, line_no, format!("Unknown material '{}'", name))
+                })?;
+                current = match builder_by_name.get(name) {
+                    Some(&index) => index,
+                    None => {
+                        builders.push(MeshBuilder::new(material));
+                        builders.len() - 1
+                    }
+                };
+                builder_by_name.insert(name.to_string(), current);
+            },
+            // Groups, objects, smoothing groups, lines and so on don't affect what we render.
+            Some(_) => {}
+        }
+    }
+    Ok(builders.into_iter()
+        .filter(|b| !b.triangles.is_empty())
+        .map(|b| b.build())
+        .collect())
+}
+
+// Loads an OBJ file (and any material libraries it references) as one mesh per material.
+pub fn load_obj_meshes<P: AsRef<Path>>(path: P) -> Result<Vec<TriangleMesh>> {
+    let path = path.as_ref();
+    let reader = open(path)?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    parse_obj(reader, &path.display().to_string(), base_dir)
+}
+
+// Loads an OBJ file (and any material libraries it references) into the scene.
+pub fn load_obj<P: AsRef<Path>>(path: P, scene: &mut Scene) -> Result<()> {
+    for mesh in load_obj_meshes(path)? {
+        scene.add(Box::new(mesh));
+    }
+    Ok(())
+}
+
+#[test]
+fn parses_faces_and_materials() {
+    let mtl = "newmtl light\nKd 0 0 0\nKe 4 4 4\n\nnewmtl glass\nillum 7\nTf 0.9 1 0.9\nNi 1.33\n";
+    let materials = parse_mtl(mtl.as_bytes(), "test.mtl", Path::new(".")).unwrap();
+    assert_eq!(materials["light"].emission.x, 4.0);
+    match materials["glass"].material.interior() {
+        Some(interior) => assert_eq!(interior.ior, 1.33),
+        None => panic!("glass should be refractive")
+    }
+
+    let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1 4//1\nf -4 -2 -1\n";
+    let meshes = parse_obj(obj.as_bytes(), "test.obj", Path::new(".")).unwrap();
+    assert_eq!(meshes.len(), 1);
+    assert_eq!(meshes[0].num_triangles(), 3);
+
+    // Texture coordinates are interpolated across faces.
+    use geometry::Ray;
+    use renderable::Renderable;
+    let obj = "v 0 0 1\nv 1 0 1\nv 0 1 1\nvt 0 0\nvt 1 0\nvt 0 0.5\nf 1/1 2/2 3/3\n";
+    let meshes = parse_obj(obj.as_bytes(), "test.obj", Path::new(".")).unwrap();
+    let ray = Ray::new(Vec3d::new(0.5, 0.5, 0.0), Vec3d::new(0.0, 0.0, 1.0));
+    let hit = meshes[0].get_hit(&ray, 1.0);
+    assert!((hit.uv.0 - 0.5).abs() < 1e-9 && (hit.uv.1 - 0.25).abs() < 1e-9);
+}
+
+#[test]
+fn reports_line_numbers() {
+    let check = |result: Result<()>, expected_line: usize| match result {
+        Err(LoadError::ParseError { line, .. }) => assert_eq!(line, expected_line),
+        _ => panic!("expected a parse error")
+    };
+    let obj = |text: &str| parse_obj(text.as_bytes(), "test.obj", Path::new(".")).map(|_| ());
+    let mtl = |text: &str| parse_mtl(text.as_bytes(), "test.mtl", Path::new(".")).map(|_| ());
+    check(obj("v 0 0 0\nv 1 0 0\n\nf 1 2 3\n"), 4);
+    check(obj("v 0 0 0\nv 1 inf 0\n"), 2);
+    check(obj("v 0 0 0\nvn 0 0 NaN\n"), 2);
+    check(obj("v 0 0 0\n\nvn 0 0 0\n"), 3);
+    check(mtl("newmtl glass\nNi 0\n"), 2);
+    check(mtl("newmtl glass\nKd 1 1 1\nNi -1.5\n"), 3);
+}
@@ -1,19 +1,57 @@
+use bvh::Aabb;
 use geometry::Ray;
-use material::Material;
-use math::{Vec3d, F64Rng};
+use bsdf::Bsdf;
+use math::{Onb, Vec3d, F64Rng};
+use texture::NormalMap;
 
 pub struct Hit<'a> {
     pub pos: Vec3d,
+    // The shading normal, which may be interpolated across a face and so differ from the true
+    // one. Materials scatter around it.
     pub normal: Vec3d,
-    pub material: &'a Material,
+    // The true normal of the surface, on the same side as the shading normal. It decides which
+    // side of the surface a direction is on.
+    pub geometric_normal: Vec3d,
+    pub material: &'a Bsdf,
     pub emission: Vec3d,
-    pub colour: Vec3d
+    pub colour: Vec3d,
+    // Surface coordinates, for texturing.
+    pub uv: (f64, f64),
+    // How the position changes with u and v: tangents to the surface, not necessarily
+    // normalised or perpendicular.
+    pub dpdu: Vec3d,
+    pub dpdv: Vec3d,
+    // The object that was hit.
+    pub object: &'a Renderable
+}
+
+impl<'a> Hit<'a> {
+    // A frame around the shading normal, lined up with the u direction where there is one.
+    pub fn shading_frame(&self) -> Onb {
+        Onb::from_w_u(self.normal, self.dpdu)
+    }
+
+    // Perturbs the shading normal with a normal map, if there is one.
+    pub fn with_normal_map(mut self, map: Option<&NormalMap>) -> Hit<'a> {
+        if let Some(map) = map {
+            self.normal = map.shading_normal(&self);
+        }
+        self
+    }
 }
 
 pub trait Renderable: Send + Sync {
     fn intersect(&self, ray: &Ray) -> Option<f64>;
     fn get_hit(&self, ray: &Ray, dist: f64) -> Hit;
+    fn bounding_box(&self) -> Aabb;
     fn is_emissive(&self) -> bool;
-    fn random_emission(&self, from: Vec3d, normal: Vec3d, rng: &mut F64Rng) -> (Vec3d, Vec3d);
+    // Picks a direction from `from` towards a random point on the object as it is at `time`,
+    // returning it with the radiance emitted back along it and the pdf (with respect to solid
+    // angle) of choosing it. A pdf of zero means no direction could be chosen.
+    fn random_emission(&self, from: Vec3d, time: f64, rng: &mut F64Rng) -> (Vec3d, Vec3d, f64);
+    // The pdf with which random_emission would choose `direction` from `from` at `time`.
+    fn emission_pdf(&self, from: Vec3d, direction: Vec3d, time: f64) -> f64;
+    // The luminous power the object emits, used to decide how often to sample it.
+    fn power(&self) -> f64;
     fn identity(&self) -> u64;
 }
@@ -0,0 +1,157 @@
+// Warping uniform random numbers into other distributions.
+use std::f64::consts::PI;
+
+// Maps a point in the unit square to the unit disc, preserving relative areas and keeping
+// nearby points nearby (Shirley & Chiu's concentric mapping).
+pub fn concentric_disc(u1: f64, u2: f64) -> (f64, f64) {
+    let (a, b) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+    if a == 0.0 && b == 0.0 { return (0.0, 0.0); }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, (PI / 4.0) * (b / a))
+    } else {
+        (b, PI / 2.0 - (PI / 4.0) * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+// Uniformly samples a regular polygon with `sides` sides inscribed in the unit circle, with its
+// first vertex at `rotation` radians.
+pub fn regular_polygon(sides: u32, rotation: f64, u1: f64, u2: f64, u3: f64) -> (f64, f64) {
+    let sides = sides.max(3);
+    let wedge = ((u1 * sides as f64) as u32).min(sides - 1);
+    let angle = 2.0 * PI / sides as f64;
+    let (a0, a1) = (rotation + angle * wedge as f64, rotation + angle * (wedge + 1) as f64);
+    // Uniform point in the triangle (centre, vertex a0, vertex a1).
+    let su = u2.sqrt();
+    let (b0, b1) = (su * (1.0 - u3), su * u3);
+    (b0 * a0.cos() + b1 * a1.cos(), b0 * a0.sin() + b1 * a1.sin())
+}
+
+// Veach's power heuristic (with an exponent of two) for weighting a sample taken with pdf
+// `f_pdf` against another strategy that would have chosen it with `g_pdf`.
+pub fn power_heuristic(f_pdf: f64, g_pdf: f64) -> f64 {
+    let (f, g) = (f_pdf * f_pdf, g_pdf * g_pdf);
+    if f + g == 0.0 || f.is_infinite() { 1.0 } else { f / (f + g) }
+}
+
+// A piecewise-constant distribution over [0, 1), with one piece per function value.
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Distribution1D {
+        let n = func.len();
+        assert!(n > 0, "Can't build a distribution from no values");
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for i in 0..n {
+            let previous = cdf[i];
+            cdf.push(previous + func[i].abs() / n as f64);
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+            // An all-zero function is treated as uniform so we can still sample it.
+            *c = if integral == 0.0 { i as f64 / n as f64 } else { *c / integral };
+        }
+        Distribution1D { func: func, cdf: cdf, integral: integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // Returns a sample in [0, 1), its pdf, and the index of the piece it fell in.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let offset = self.find_interval(u);
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if width > 0.0 { (u - self.cdf[offset]) / width } else { 0.0 };
+        let x = (offset as f64 + du) / self.count() as f64;
+        (x.min(1.0 - 1e-12), self.pdf_at(offset), offset)
+    }
+
+    // Returns the index of a piece, and the probability of having chosen it.
+    pub fn sample_discrete(&self, u: f64) -> (usize, f64) {
+        let offset = self.find_interval(u);
+        (offset, self.cdf[offset + 1] - self.cdf[offset])
+    }
+
+    pub fn discrete_pdf(&self, index: usize) -> f64 {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    // The density of the continuous distribution in the given piece.
+    pub fn pdf_at(&self, index: usize) -> f64 {
+        if self.integral == 0.0 { 1.0 } else { self.func[index].abs() / self.integral }
+    }
+
+    // Finds the last cdf entry <= u, by binary search.
+    fn find_interval(&self, u: f64) -> usize {
+        let (mut lo, mut hi) = (0, self.count());
+        while lo + 1 < hi {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid] <= u { lo = mid; } else { hi = mid; }
+        }
+        lo
+    }
+}
+
+// A piecewise-constant distribution over [0, 1)^2, built from a row-major grid of values.
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], width: usize, height: usize) -> Distribution2D {
+        assert_eq!(func.len(), width * height);
+        let conditional: Vec<Distribution1D> = func.chunks(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+        Distribution2D { conditional: conditional, marginal: marginal }
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.marginal.integral()
+    }
+
+    // Returns a point (x, y) in [0, 1)^2 and its pdf with respect to area in that square.
+    pub fn sample_continuous(&self, u1: f64, u2: f64) -> ((f64, f64), f64) {
+        let (y, pdf_y, row) = self.marginal.sample_continuous(u2);
+        let (x, pdf_x, _) = self.conditional[row].sample_continuous(u1);
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, x: f64, y: f64) -> f64 {
+        let row = ((y * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        let conditional = &self.conditional[row];
+        let column = ((x * conditional.count() as f64) as usize).min(conditional.count() - 1);
+        if self.marginal.integral() == 0.0 { return 1.0; }
+        conditional.func[column].abs() / self.marginal.integral()
+    }
+}
+
+#[test]
+fn distribution_2d_follows_its_function() {
+    // Only the bottom right cell has any weight, and it's four times the bottom left's.
+    let dist = Distribution2D::new(&[0.0, 0.0, 1.0, 4.0], 2, 2);
+    let mut in_bottom_right = 0;
+    for i in 0..1000 {
+        let u1 = (i as f64 + 0.5) / 1000.0;
+        let ((x, y), pdf) = dist.sample_continuous(u1, 0.5);
+        assert!(y >= 0.5);
+        assert_eq!(pdf, dist.pdf(x, y));
+        if x >= 0.5 { in_bottom_right += 1; }
+    }
+    assert_eq!(in_bottom_right, 800);
+    assert_eq!(dist.pdf(0.75, 0.75), 3.2);
+}
@@ -1,43 +1,119 @@
+use bvh::{Aabb, Bvh};
 use geometry::*;
+use bsdf::BsdfFlags;
+use light::Light;
 use math::*;
+use medium::{Medium, MediumStack};
 use renderable::{Hit, Renderable};
+use sampling::{power_heuristic, Distribution1D};
 
+use std::collections::HashMap;
 use std::f64;
 
+// Something light sampling can pick: an emissive object or a light, by index.
+#[derive(Debug, Clone, Copy)]
+enum Emitter {
+    Object(usize),
+    Light(usize)
+}
+
 pub struct Scene {
-    objects: Vec<Box<Renderable>>
+    objects: Vec<Box<Renderable>>,
+    // Lights other than emissive objects.
+    lights: Vec<Box<Light>>,
+    bvh: Option<Bvh>,
+    // Every emissive object and light, in the order they were added.
+    emitters: Vec<Emitter>,
+    // Where each emissive object (by identity) and each light is in `emitters`.
+    object_emitters: HashMap<u64, usize>,
+    light_emitters: Vec<usize>,
+    // Picks emitters in proportion to their power. Until it's built they're picked uniformly.
+    light_distribution: Option<Distribution1D>,
+    // Fog filling all the space outside any other medium.
+    medium: Option<Medium>
 }
 
 impl Scene {
     pub fn new() -> Scene {
-        Scene { objects: Vec::new() }
+        Scene {
+            objects: Vec::new(),
+            lights: Vec::new(),
+            bvh: None,
+            emitters: Vec::new(),
+            object_emitters: HashMap::new(),
+            light_emitters: Vec::new(),
+            light_distribution: None,
+            medium: None
+        }
     }
     pub fn add(&mut self, object: Box<Renderable>) {
+        if object.is_emissive() {
+            self.object_emitters.insert(object.identity(), self.emitters.len());
+            self.emitters.push(Emitter::Object(self.objects.len()));
+            self.light_distribution = None;
+        }
         self.objects.push(object);
+        // Any existing hierarchy no longer covers every object.
+        self.bvh = None;
+    }
+    // Fills the scene with fog. Nothing gets through it from infinitely far away, so it leaves
+    // distant lights like the sun and sky in the dark.
+    pub fn set_medium(&mut self, medium: Medium) {
+        self.medium = Some(medium);
+    }
+    pub fn medium(&self) -> Option<Medium> {
+        self.medium
+    }
+    pub fn add_light(&mut self, light: Box<Light>) {
+        self.light_emitters.push(self.emitters.len());
+        self.emitters.push(Emitter::Light(self.lights.len()));
+        self.lights.push(light);
+        self.light_distribution = None;
+    }
+    // Builds the acceleration structure over everything added so far. Until this is called
+    // (and again after any further add) every ray is tested against every object.
+    pub fn build_bvh(&mut self) {
+        let bounds: Vec<Aabb> = self.objects.iter().map(|obj| obj.bounding_box()).collect();
+        self.bvh = Some(Bvh::new(&bounds));
+    }
+    // Works out how often to pick each emitter when sampling only some of them, from their
+    // power. Like build_bvh, this needs calling again after anything else is added.
+    pub fn build_light_distribution(&mut self) {
+        if self.emitters.is_empty() { return; }
+        let bounds = self.objects.iter().fold(Aabb::empty(), |b, obj| b.union(obj.bounding_box()));
+        let radius = if bounds.is_empty() { 1.0 } else { bounds.extent().length() * 0.5 };
+        let powers: Vec<f64> = self.emitters.iter().map(|&emitter| match emitter {
+            Emitter::Object(index) => self.objects[index].power(),
+            Emitter::Light(index) => self.lights[index].power(radius)
+        }).collect();
+        // The estimates can be way off (distant lights depend on the size of the scene, which
+        // something like a huge floor sphere inflates), so every emitter keeps a small share
+        // of the samples whatever its power.
+        const UNIFORM_SHARE: f64 = 0.1;
+        let total: f64 = powers.iter().sum();
+        let uniform = UNIFORM_SHARE / powers.len() as f64;
+        let weights = powers.iter().map(|&power| {
+            if total > 0.0 { (1.0 - UNIFORM_SHARE) * power / total + uniform } else { uniform }
+        }).collect();
+        self.light_distribution = Some(Distribution1D::new(weights));
     }
     pub fn intersect<'a>(&'a self, ray: &Ray) -> Option<Hit<'a>> {
-        let mut hit_dist = f64::INFINITY;
-        let mut hit_obj: Option<&Box<Renderable>> = None;
-        for obj in self.objects.iter() {
-            if let Some(dist) = obj.intersect(&ray) {
-                if dist < hit_dist {
-                    hit_dist = dist;
-                    hit_obj = Some(&obj);
-                }
-            }
-        }
+        self.closest_object(ray).map(|(obj, dist)| obj.get_hit(ray, dist))
+    }
 
-        match hit_obj {
-            None => { None },
-            Some(obj) => {
-                Some(obj.get_hit(&ray, hit_dist))
-            }
+    fn closest_object(&self, ray: &Ray) -> Option<(&Renderable, f64)> {
+        match self.bvh {
+            Some(ref bvh) => {
+                bvh.intersect(ray, |index| self.objects[index].intersect(ray))
+                    .map(|(index, dist)| (&*self.objects[index], dist))
+            },
+            None => self.closest_object_brute_force(ray)
         }
     }
 
-    fn shadow_cast(&self, ray: &Ray, light: &Renderable) -> bool {
-        let mut hit_obj: Option<&Renderable> = None;
+    fn closest_object_brute_force(&self, ray: &Ray) -> Option<(&Renderable, f64)> {
         let mut hit_dist = f64::INFINITY;
+        let mut hit_obj: Option<&Renderable> = None;
         for obj in self.objects.iter() {
             if let Some(dist) = obj.intersect(&ray) {
                 if dist < hit_dist {
@@ -46,27 +122,222 @@ impl Scene {
                 }
             }
         }
-        match hit_obj {
-            None => { false },
-            Some(obj) => {
-                // Ideally, something like this:
-                // if obj as *const Renderable == light as *const Renderable { true } else { false }
-                // but we hit an ICE in rust 1.0.0
-                obj.identity() == light.identity()
+        hit_obj.map(|obj| (obj, hit_dist))
+    }
+
+    // How much of the light travelling back along `ray` from `light`, or from `distance` away if
+    // that's None, makes it to the ray's origin through `media`. Anything in the way blocks it,
+    // apart from the invisible surfaces of media, which it's attenuated by the insides of.
+    fn transmittance(&self, ray: &Ray, light: Option<&Renderable>, distance: f64, media: &MediumStack,
+                     rng: &mut F64Rng) -> Vec3d {
+        const EPSILON: f64 = 1e-6;
+        let (mut ray, mut distance, mut media) = (*ray, distance, media.clone());
+        let mut transmittance = Vec3d::one();
+        loop {
+            let (obj, dist) = match self.closest_object(&ray) {
+                Some(closest) => closest,
+                None if light.is_some() => return Vec3d::zero(),
+                None => return transmittance * media.transmittance(distance, rng)
+            };
+            match light {
+                Some(light) if obj.identity() == light.identity() => {
+                    return transmittance * media.transmittance(dist, rng);
+                },
+                None if dist >= distance * (1.0 - EPSILON) => {
+                    return transmittance * media.transmittance(distance, rng);
+                },
+                _ => {}
             }
+            let hit = obj.get_hit(&ray, dist);
+            if !hit.material.flags().contains(BsdfFlags::NULL) { return Vec3d::zero(); }
+            transmittance = transmittance * media.transmittance(dist, rng);
+            if transmittance.max_component() <= 0.0 { return transmittance; }
+            media = media.crossing(hit.material, hit.geometric_normal.dot(ray.direction) < 0.0);
+            ray = Ray::new(hit.pos, ray.direction).with_time(ray.time);
+            distance -= dist;
+        }
+    }
+
+    fn emitter_probability(&self, index: usize) -> f64 {
+        match self.light_distribution {
+            Some(ref distribution) => distribution.discrete_pdf(index),
+            None => 1.0 / self.emitters.len() as f64
         }
     }
 
-    pub fn sample_lights(&self, from: Vec3d, normal: Vec3d, rng: &mut F64Rng) -> Vec3d {
+    // How many times, on average, sample_lights samples an emitter: once each if
+    // `light_samples` is zero, and otherwise according to its power.
+    fn emitter_rate(&self, index: usize, light_samples: usize) -> f64 {
+        if light_samples == 0 { 1.0 } else { light_samples as f64 * self.emitter_probability(index) }
+    }
+
+    // Estimates the light arriving at `from` at `time` that isn't in shadow, through `media`.
+    // With `light_samples` of zero every emissive object and light is sampled once; otherwise
+    // that many are picked in proportion to their power, so the cost doesn't grow with the
+    // number of lights. `bsdf` gives the factor to apply to light from a direction and the pdf
+    // of the BSDF sampling it, so the two strategies can be combined with multiple importance
+    // sampling.
+    pub fn sample_lights<F: Fn(Vec3d) -> (Vec3d, f64)>(&self, from: Vec3d, time: f64, media: &MediumStack,
+                                                      light_samples: usize, rng: &mut F64Rng, bsdf: F) -> Vec3d {
         let mut emission = Vec3d::zero();
-        for obj in self.objects.iter() {
-            if !obj.is_emissive() { continue; }
-            let (random_obj_dir, obj_emission) = obj.random_emission(from, normal, rng);
-            let ray = Ray::new(from, random_obj_dir);
-            if self.shadow_cast(&ray, &**obj) {
-                emission = emission + obj_emission;
+        if self.emitters.is_empty() { return emission; }
+        let estimate = |index: usize, rng: &mut F64Rng| {
+            // How many times this emitter is sampled per call on average.
+            let rate = self.emitter_rate(index, light_samples);
+            match self.sample_emitter(index, from, time, media, rng) {
+                Some((direction, radiance, pdf, delta)) => {
+                    let (factor, bsdf_pdf) = bsdf(direction);
+                    // BSDF sampling can never find delta lights, so there's nothing to weight
+                    // them against.
+                    let weight = if delta { 1.0 } else { power_heuristic(pdf * rate, bsdf_pdf) };
+                    factor * radiance * (weight / (pdf * rate))
+                },
+                None => Vec3d::zero()
+            }
+        };
+        if light_samples == 0 {
+            for index in 0..self.emitters.len() {
+                emission = emission + estimate(index, rng);
+            }
+        } else {
+            for _ in 0..light_samples {
+                let index = match self.light_distribution {
+                    Some(ref distribution) => distribution.sample_discrete(rng.next()).0,
+                    None => ((rng.next() * self.emitters.len() as f64) as usize).min(self.emitters.len() - 1)
+                };
+                emission = emission + estimate(index, rng);
             }
         }
         emission
     }
+
+    // Samples a direction towards one emitter. Returns it with the light arriving from it
+    // through `media`, the pdf of choosing it and whether the emitter is a delta light, or None
+    // if there's nothing to add.
+    fn sample_emitter(&self, index: usize, from: Vec3d, time: f64, media: &MediumStack,
+                      rng: &mut F64Rng) -> Option<(Vec3d, Vec3d, f64, bool)> {
+        match self.emitters[index] {
+            Emitter::Object(object) => {
+                let obj = &*self.objects[object];
+                let (direction, radiance, pdf) = obj.random_emission(from, time, rng);
+                if pdf <= 0.0 { return None; }
+                let ray = Ray::new(from, direction).with_time(time);
+                let transmittance = self.transmittance(&ray, Some(obj), f64::INFINITY, media, rng);
+                if transmittance.max_component() <= 0.0 { return None; }
+                Some((direction, radiance * transmittance, pdf, false))
+            },
+            Emitter::Light(light) => {
+                let light = &*self.lights[light];
+                let sample = light.sample(from, rng)?;
+                if sample.pdf <= 0.0 { return None; }
+                let ray = Ray::new(from, sample.direction).with_time(time);
+                let transmittance = self.transmittance(&ray, None, sample.distance, media, rng);
+                if transmittance.max_component() <= 0.0 { return None; }
+                Some((sample.direction, sample.radiance * transmittance, sample.pdf, light.is_delta()))
+            }
+        }
+    }
+
+    // The light arriving along a ray that escapes the scene in `direction`. If it was chosen by
+    // sampling a BSDF with pdf `bsdf_pdf`, it's weighted against the chance of light sampling
+    // having found it instead.
+    pub fn background(&self, direction: Vec3d, bsdf_pdf: Option<(f64, Vec3d)>, light_samples: usize) -> Vec3d {
+        let mut radiance = Vec3d::zero();
+        for (index, light) in self.lights.iter().enumerate() {
+            let weight = match bsdf_pdf {
+                Some((pdf, from)) if !light.is_delta() => {
+                    let rate = self.emitter_rate(self.light_emitters[index], light_samples);
+                    power_heuristic(pdf, light.pdf(from, direction) * rate)
+                },
+                _ => 1.0
+            };
+            radiance = radiance + light.background(direction) * weight;
+        }
+        radiance
+    }
+
+    // The pdf with which sample_lights would have picked `direction` from `from` towards
+    // `object`, which it must hit, scaled by how often it samples the object.
+    pub fn light_pdf(&self, from: Vec3d, direction: Vec3d, time: f64, object: &Renderable, light_samples: usize) -> f64 {
+        match self.object_emitters.get(&object.identity()) {
+            Some(&index) => object.emission_pdf(from, direction, time) * self.emitter_rate(index, light_samples),
+            None => 0.0
+        }
+    }
+}
+
+#[test]
+fn bvh_matches_brute_force() {
+    use material::Lambertian;
+    use std::sync::Arc;
+    use mesh::Triangle;
+    use rand::{SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let random_point = |rng: &mut XorShiftRng, scale: f64| {
+        Vec3d::new(rng.next() - 0.5, rng.next() - 0.5, rng.next() - 0.5) * scale
+    };
+    let mut scene = Scene::new();
+    for _ in 0..500 {
+        let centre = random_point(&mut rng, 100.0);
+        let radius = rng.next() * 3.0;
+        scene.add(Box::new(Sphere::new(Arc::new(Lambertian), radius, centre, Vec3d::zero(), Vec3d::one())));
+        let corner = random_point(&mut rng, 100.0);
+        let vertices = [corner, corner + random_point(&mut rng, 10.0), corner + random_point(&mut rng, 10.0)];
+        scene.add(Box::new(Triangle::new(Arc::new(Lambertian), vertices, Vec3d::zero(), Vec3d::one())));
+    }
+    // A huge enclosing sphere, like the walls of the Cornell box.
+    scene.add(Box::new(Sphere::new(Arc::new(Lambertian), 1e5, Vec3d::new(0.0, 0.0, 1e5 + 60.0),
+                                   Vec3d::zero(), Vec3d::one())));
+    scene.build_bvh();
+
+    let mut hits = 0;
+    for _ in 0..5000 {
+        let origin = random_point(&mut rng, 120.0);
+        let direction = random_point(&mut rng, 1.0).normalized();
+        let ray = Ray::new(origin, direction);
+        let expected = scene.closest_object_brute_force(&ray);
+        let actual = scene.closest_object(&ray);
+        match (expected, actual) {
+            (None, None) => {},
+            (Some((e, e_dist)), Some((a, a_dist))) => {
+                assert_eq!(e.identity(), a.identity());
+                assert_eq!(e_dist, a_dist);
+                hits += 1;
+            },
+            _ => panic!("BVH and brute force disagree about {:?}", ray)
+        }
+    }
+    assert!(hits > 1000);
+}
+
+#[test]
+fn power_sampling_matches_sampling_every_light() {
+    use material::Lambertian;
+    use light::PointLight;
+    use std::sync::Arc;
+    use rand::{SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let mut scene = Scene::new();
+    scene.add(Box::new(Sphere::new(Arc::new(Lambertian), 1.0, Vec3d::new(0.0, 5.0, 0.0), Vec3d::one() * 10.0, Vec3d::zero())));
+    scene.add(Box::new(Sphere::new(Arc::new(Lambertian), 0.5, Vec3d::new(3.0, 4.0, 0.0), Vec3d::one() * 2.0, Vec3d::zero())));
+    scene.add_light(Box::new(PointLight::new(Vec3d::new(-2.0, 3.0, 1.0), Vec3d::one() * 5.0)));
+    scene.build_bvh();
+    scene.build_light_distribution();
+
+    let estimate = |light_samples: usize, rng: &mut XorShiftRng| {
+        let n = 20000;
+        let mut sum = Vec3d::zero();
+        for _ in 0..n {
+            sum = sum + scene.sample_lights(Vec3d::zero(), 0.0, &MediumStack::new(None), light_samples, rng, |_| (Vec3d::one(), 0.0));
+        }
+        sum.y / n as f64
+    };
+    let every_light = estimate(0, &mut rng);
+    let by_power = estimate(1, &mut rng);
+    assert!((by_power - every_light).abs() < 0.03 * every_light, "{} vs {}", by_power, every_light);
+    // The big sphere is by far the most powerful.
+    let sphere = scene.emitter_probability(0);
+    assert!(sphere > scene.emitter_probability(1) && sphere > scene.emitter_probability(2));
 }
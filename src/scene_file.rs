@@ -0,0 +1,996 @@
+// Text scene descriptions. Each non-blank line is a directive followed by named properties, which
+// may be given in any order. '#' starts a comment. For example:
+//
+//   render width 1024 height 768 samples 16 max_depth 64 light_samples 1
+//   camera position 50 52 295.6 direction 0 -0.042612 -1 fov 28.8 near 140
+//   camera position 0 1 5 target 0 1 0 up 0 1 0 fov 40 aperture 0.1 blades 6 rotation 15
+//   camera position 0 1 5 target 0 1 0 shutter 0.25 0.75 end_position 1 1 5 end_target 0 1 0
+//   texture checks checker even 0.9 0.9 0.9 odd 0.1 0.1 0.1 scale 8
+//   texture wood image file wood.png wrap mirror
+//   texture ripples image file ripples.png linear
+//   texture marble noise low 0.2 0.2 0.3 high 0.9 0.9 0.9 scale 4 octaves 6
+//   material red diffuse colour 0.75 0.25 0.25
+//   material floor diffuse texture checks
+//   material pond specular normal_map ripples
+//   material plaster diffuse bump marble 0.01
+//   material water refractive ior 1.33 absorption 0.02 0.005 0.001
+//   material brushed conductor metal gold roughness 0.3
+//   material scuffed conductor metal copper roughness_texture marble
+//   material frosted rough_refractive ior 1.5 roughness 0.2 distribution beckmann
+//   material smoke medium absorption 0.05 0.05 0.05 scattering 0.8 0.8 0.8 g 0.3
+//   fog absorption 0.001 0.001 0.001 scattering 0.02 0.02 0.02 g 0.6
+//   sphere red radius 1e5 centre 100001 40.8 81.6
+//   sphere red radius 1 centre 0 1 0 end_centre 0.5 1 0
+//   triangle red vertices 0 0 0 1 0 0 0 1 0
+//   quad red corner 0 0 0 u 1 0 0 v 0 0 1
+//   disc red centre 0 0 0 normal 0 1 0 radius 2
+//   obj models/teapot.obj
+//   mesh teapot file models/teapot.obj
+//   instance teapot scale 2 2 2 rotate 0 1 0 45 translate 0 0 -5 material red
+//   instance teapot at 0 translate 0 0 -5 at 1 rotate 0 1 0 90 translate 1 0 0
+//   light sphere radius 1.5 centre 50 65.1 81.6 emission 400 400 400
+//   light quad corner -1 5 -1 u 2 0 0 v 0 0 2 emission 10 10 10
+//   light disc centre 0 5 0 normal 0 -1 0 radius 1 emission 10 10 10 two_sided
+//   light point position 0 5 0 intensity 50 50 50
+//   light spot position 0 5 0 target 0 0 0 intensity 100 100 100 cone 30 edge 5
+//   light directional direction -1 -2 -1 irradiance 3 3 3
+//   light environment image sky.hdr rotation 90 intensity 1.5
+//   light sky elevation 30 azimuth 120 turbidity 3 intensity 0.01
+//
+// A camera with an aperture has depth of field. It focuses on its target unless given a focus
+// distance, and its aperture is circular unless given a number of blades or a bokeh image.
+//
+// Time runs from 0 to 1 over each frame, and the camera's shutter is open for all of it unless
+// given the times it opens and closes. Anything that moves while it's open is blurred: the
+// camera itself, if given an end_position or an end_direction or end_target to pan to, and
+// spheres given an end_centre, both moving at a steady rate from 0 to 1.
+//
+// Textures replace a material's colour. Checkers and images are mapped with each shape's
+// surface coordinates: longitude and latitude on spheres, along the edges of quads and triangles,
+// and around and out from the centre of discs. Images wrap by repeating unless told to clamp or
+// mirror, and noise is a solid texture of position, with features about 1 / scale apart.
+//
+// Materials can add detail to a surface's shading with a tangent space normal_map, or a bump
+// map given a texture and the height its brightest parts raise the surface by. Images holding
+// data like this rather than colours should be read as linear.
+//
+// A medium material makes the shape it's given to an invisible container of smoke, fog or the
+// like, which absorbs and scatters light at the given rates per unit distance, towards
+// directions chosen by a Henyey-Greenstein phase function. Positive g scatters light onwards,
+// and negative g back the way it came. The shape should be closed, and the camera outside it.
+// Fog fills the whole scene instead, apart from inside other media and refractive objects, and
+// blocks all light coming from infinitely far away, like the sun and sky. Paths never escape it,
+// so without some absorption every one goes on until max_depth.
+//
+// Conductors take a named metal (gold, copper, aluminium or silver) or their own eta and k.
+// Rough materials use the GGX distribution unless told otherwise, and can take their roughness
+// from the brightness of a texture instead of a fixed roughness.
+//
+// Quads face along u x v and discs along their normal. As emitters, they only light what's in
+// front of them unless marked two_sided.
+//
+// Point and spot lights fall off with the square of distance unless given another falloff
+// exponent. A spot light's cone is its half-angle in degrees, the last `edge` degrees of which
+// fade out smoothly. Directional lights take the direction the light travels in.
+// Environment images are equirectangular .hdr or .pfm files, rotated in degrees about the
+// vertical axis.
+//
+// The sky is an analytic daylight model in kcd/m^2, lit by a sun at the given elevation and
+// azimuth (clockwise from -z towards +x) in degrees. Turbidity runs from about 2 for a clear
+// sky to 10 for a hazy one. It's baked into an image `resolution` pixels wide, which must be at
+// least 2, and no_sun leaves out the sun's disc.
+//
+// OBJ meshes whose MTL material has an emissive (Ke) colour are lights too.
+//
+// A mesh is only loaded once, however many instances of it there are, and isn't drawn itself.
+// Each instance's transforms apply in the order given, with rotations by degrees about an axis.
+// Giving an instance a material replaces the material and colour of every part of its mesh.
+// Instances are animated with keyframes, each starting with `at` and a time. Within one,
+// translations add to where the instance is, rotations turn it further, and scales stretch it
+// further, carrying on from the keyframe before. Transforms before the first keyframe apply
+// first, and between keyframes rotations take the shortest way round.
+//
+// Every light is sampled at each bounce unless the render gives a number of light_samples, in
+// which case that many are picked in proportion to their power. That's much faster in scenes
+// with lots of lights.
+//
+// Materials must be defined before they're used; file paths are relative to the scene file.
+use camera::{Aperture, Camera, PinholeCamera, ShutterCamera, ThinLensCamera};
+use error::LoadError;
+use geometry::{Disc, Quad, Sphere};
+use light::{DirectionalLight, EnvironmentLight, PointLight, SpotLight};
+use sky::{sun_direction, PreethamSky};
+use texture::{constant_texture, Checkerboard, ImageTexture, NoiseTexture, NormalMap, Perlin, Texture, WrapMode};
+use material::{Dielectric, Lambertian, Material, MediumBoundary, Mirror, RoughConductor, RoughDielectric};
+use instance::Instance;
+use math::{AnimatedTransform, Keyframe, Quaternion, Transform, Vec3d};
+use medium::Medium;
+use mesh::Triangle;
+use microfacet::{conductor_preset, MicrofacetDistribution, MicrofacetType};
+use obj::{load_obj, load_obj_meshes};
+use renderable::Renderable;
+use scene::Scene;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+type Result<T> = ::std::result::Result<T, LoadError>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    // Light that reaches the camera after fewer bounces than this isn't recorded: 1 hides
+    // emitters seen directly, 2 gives indirect light only.
+    pub min_depth: usize,
+    // Paths are cut off after this many bounces.
+    pub max_depth: usize,
+    // The bounce from which paths are randomly terminated according to their throughput.
+    pub roulette_depth: usize,
+    // How many lights to sample at each bounce, chosen by their power. Zero samples every light.
+    pub light_samples: usize
+}
+
+impl RenderSettings {
+    pub fn new() -> RenderSettings {
+        RenderSettings { width: 1024, height: 768, samples: 4, min_depth: 0, max_depth: 500, roulette_depth: 5, light_samples: 0 }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings::new()
+    }
+}
+
+// The camera can't be built until the image's aspect ratio is final, as that may be overridden
+// after the scene has been loaded.
+#[derive(Debug, Clone)]
+pub struct CameraDescription {
+    pub position: Vec3d,
+    pub direction: Vec3d,
+    pub up: Vec3d,
+    // Vertical field of view, in degrees.
+    pub fov: f64,
+    // Distance along the view direction at which rays start.
+    pub near: f64,
+    // A lens radius of zero gives a pinhole camera, with no depth of field.
+    pub lens_radius: f64,
+    pub focus_distance: f64,
+    pub aperture: Aperture,
+    // The part of the frame, from 0 to 1, that the shutter is open for.
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    // If the camera moves, where it's got to and the direction it's looking in by the end of
+    // the frame.
+    pub end_pose: Option<(Vec3d, Vec3d)>
+}
+
+impl CameraDescription {
+    pub fn camera(&self, aspect: f64) -> Box<Camera> {
+        let pinhole = PinholeCamera::new(self.position, self.direction, self.up, self.fov, aspect)
+            .with_near(self.near);
+        let camera: Box<Camera> = if self.lens_radius > 0.0 {
+            Box::new(ThinLensCamera::new(pinhole, self.lens_radius, self.focus_distance, self.aperture.clone()))
+        } else {
+            Box::new(pinhole)
+        };
+        let camera = ShutterCamera::new(camera, self.shutter_open, self.shutter_close);
+        match self.end_pose {
+            Some((position, direction)) => {
+                let poses = vec![self.pose(0.0, self.position, self.direction), self.pose(1.0, position, direction)];
+                Box::new(camera.with_poses(AnimatedTransform::new(poses)))
+            },
+            None => Box::new(camera)
+        }
+    }
+
+    fn pose(&self, time: f64, position: Vec3d, direction: Vec3d) -> Keyframe {
+        let right = direction.cross(self.up).normalized();
+        let mut pose = Keyframe::new(time);
+        pose.translation = position;
+        pose.rotation = Quaternion::from_basis(right, right.cross(direction), direction.neg());
+        pose
+    }
+}
+
+pub struct SceneDescription {
+    pub scene: Scene,
+    pub camera: CameraDescription,
+    pub settings: RenderSettings
+}
+
+#[derive(Clone)]
+struct NamedMaterial {
+    material: Material,
+    colour: Vec3d,
+    // Replaces the colour, if given.
+    texture: Option<Arc<Texture>>,
+    normal_map: Option<NormalMap>,
+    emission: Vec3d,
+    // Set for the black emitters made by 'light', whose emission is given with the shape.
+    is_light: bool
+}
+
+impl NamedMaterial {
+    fn light() -> NamedMaterial {
+        NamedMaterial {
+            material: Arc::new(Lambertian),
+            colour: Vec3d::zero(),
+            texture: None,
+            normal_map: None,
+            emission: Vec3d::zero(),
+            is_light: true
+        }
+    }
+}
+
+// The remaining tokens of one directive, with helpers that report errors against its line.
+struct Directive<'a> {
+    file: &'a str,
+    line: usize,
+    keyword: &'a str,
+    tokens: ::std::str::SplitWhitespace<'a>
+}
+
+impl<'a> Directive<'a> {
+    fn error<S: Into<String>>(&self, message: S) -> LoadError {
+        LoadError::parse(self.file, self.line, message)
+    }
+    fn property(&mut self) -> Option<&'a str> {
+        self.tokens.next()
+    }
+    fn word(&mut self, what: &str) -> Result<&'a str> {
+        match self.tokens.next() {
+            Some(word) => Ok(word),
+            None => Err(self.error(format!("'{}' is missing {}", self.keyword, what)))
+        }
+    }
+    fn value<T: FromStr>(&mut self, what: &str) -> Result<T> {
+        let word = self.word(what)?;
+        word.parse().map_err(|_| self.error(format!("Bad {} '{}'", what, word)))
+    }
+    // Infinities and NaNs are never wanted, and would only cause trouble later on.
+    fn number(&mut self, what: &str) -> Result<f64> {
+        let word = self.word(what)?;
+        match word.parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok(value),
+            _ => Err(self.error(format!("Bad {} '{}'", what, word)))
+        }
+    }
+    fn positive(&mut self, what: &str) -> Result<f64> {
+        let value = self.number(what)?;
+        if value > 0.0 { Ok(value) } else { Err(self.error(format!("{} must be positive", what))) }
+    }
+    fn vec3(&mut self, what: &str) -> Result<Vec3d> {
+        let x = self.number(what)?;
+        let y = self.number(what)?;
+        let z = self.number(what)?;
+        Ok(Vec3d::new(x, y, z))
+    }
+    fn unknown(&self, property: &str) -> LoadError {
+        self.error(format!("Unknown {} property '{}'", self.keyword, property))
+    }
+    fn require<T>(&self, value: Option<T>, what: &str) -> Result<T> {
+        value.ok_or_else(|| self.error(format!("'{}' needs a {}", self.keyword, what)))
+    }
+}
+
+struct Parser<'a> {
+    base_dir: &'a Path,
+    scene: Scene,
+    camera: Option<CameraDescription>,
+    settings: RenderSettings,
+    materials: HashMap<String, NamedMaterial>,
+    textures: HashMap<String, Arc<Texture>>,
+    // Geometry for instancing, in as many parts as it has materials.
+    meshes: HashMap<String, Vec<Arc<Renderable>>>
+}
+
+impl<'a> Parser<'a> {
+    fn material(&self, d: &mut Directive) -> Result<NamedMaterial> {
+        let name = d.word("a material name")?;
+        self.materials.get(name).cloned()
+            .ok_or_else(|| d.error(format!("Unknown material '{}'", name)))
+    }
+
+    fn texture(&self, d: &mut Directive) -> Result<Arc<Texture>> {
+        let name = d.word("a texture name")?;
+        self.textures.get(name).cloned()
+            .ok_or_else(|| d.error(format!("Unknown texture '{}'", name)))
+    }
+
+    // Quads and discs, as geometry or lights. Lights are given their emission directly.
+    fn quad(&self, d: &mut Directive, m: NamedMaterial) -> Result<Quad> {
+        let (mut corner, mut u, mut v, mut emission, mut two_sided) = (None, None, None, m.emission, false);
+        while let Some(property) = d.property() {
+            match property {
+                "corner" => corner = Some(d.vec3("corner")?),
+                "u" => u = Some(d.vec3("u")?),
+                "v" => v = Some(d.vec3("v")?),
+                "emission" if m.is_light => emission = d.vec3("emission")?,
+                "two_sided" => two_sided = true,
+                _ => return Err(d.unknown(property))
+            }
+        }
+        let (corner, u, v) = (d.require(corner, "corner")?, d.require(u, "u edge")?, d.require(v, "v edge")?);
+        if u.cross(v).length_squared() == 0.0 { return Err(d.error("Quad edges can't be parallel")); }
+        let quad = Quad::new(m.material, corner, u, v, emission, m.colour);
+        let quad = match m.texture { Some(texture) => quad.with_texture(texture), None => quad };
+        let quad = match m.normal_map { Some(map) => quad.with_normal_map(map), None => quad };
+        Ok(if two_sided { quad.two_sided() } else { quad })
+    }
+
+    fn disc(&self, d: &mut Directive, m: NamedMaterial) -> Result<Disc> {
+        let (mut centre, mut normal, mut radius, mut emission, mut two_sided) = (None, None, None, m.emission, false);
+        while let Some(property) = d.property() {
+            match property {
+                "centre" => centre = Some(d.vec3("centre")?),
+                "normal" => normal = Some(d.vec3("normal")?),
+                "radius" => radius = Some(d.positive("radius")?),
+                "emission" if m.is_light => emission = d.vec3("emission")?,
+                "two_sided" => two_sided = true,
+                _ => return Err(d.unknown(property))
+            }
+        }
+        let (centre, normal) = (d.require(centre, "centre")?, d.require(normal, "normal")?);
+        let radius = d.require(radius, "radius")?;
+        if normal.length_squared() == 0.0 { return Err(d.error("Disc normal can't be zero")); }
+        let disc = Disc::new(m.material, centre, normal, radius, emission, m.colour);
+        let disc = match m.texture { Some(texture) => disc.with_texture(texture), None => disc };
+        let disc = match m.normal_map { Some(map) => disc.with_normal_map(map), None => disc };
+        Ok(if two_sided { disc.two_sided() } else { disc })
+    }
+
+    fn parse_line(&mut self, d: &mut Directive) -> Result<()> {
+        match d.keyword {
+            "render" => {
+                while let Some(property) = d.property() {
+                    match property {
+                        "width" => self.settings.width = d.value("width")?,
+                        "height" => self.settings.height = d.value("height")?,
+                        "samples" => self.settings.samples = d.value("samples")?,
+                        "min_depth" => self.settings.min_depth = d.value("min_depth")?,
+                        "max_depth" => self.settings.max_depth = d.value("max_depth")?,
+                        "roulette_depth" => self.settings.roulette_depth = d.value("roulette_depth")?,
+                        "light_samples" => self.settings.light_samples = d.value("light_samples")?,
+                        _ => return Err(d.unknown(property))
+                    }
+                }
+                if self.settings.width == 0 || self.settings.height == 0 {
+                    return Err(d.error("Image dimensions must be non-zero"));
+                }
+                if self.settings.min_depth > self.settings.max_depth {
+                    return Err(d.error("min_depth can't be more than max_depth"));
+                }
+            },
+            "camera" => {
+                if self.camera.is_some() { return Err(d.error("Camera defined twice")); }
+                let (mut position, mut direction, mut target) = (None, None, None);
+                let (mut up, mut fov, mut near) = (Vec3d::new(0.0, 1.0, 0.0), 40.0, 0.0);
+                let (mut lens_radius, mut focus_distance) = (0.0, None);
+                let (mut blades, mut rotation, mut bokeh) = (None, 0.0, None);
+                let (mut shutter_open, mut shutter_close) = (0.0, 1.0);
+                let (mut end_position, mut end_direction, mut end_target) = (None, None, None);
+                while let Some(property) = d.property() {
+                    match property {
+                        "shutter" => {
+                            shutter_open = d.number("shutter open time")?;
+                            shutter_close = d.number("shutter close time")?;
+                            if !(0.0 <= shutter_open && shutter_open <= shutter_close && shutter_close <= 1.0) {
+                                return Err(d.error("The shutter must open and close between 0 and 1, in that order"));
+                            }
+                        },
+                        "end_position" => end_position = Some(d.vec3("end position")?),
+                        "end_direction" => end_direction = Some(d.vec3("end direction")?),
+                        "end_target" => end_target = Some(d.vec3("end target")?),
+                        "aperture" => lens_radius = d.positive("aperture")?,
+                        "focus" => focus_distance = Some(d.positive("focus")?),
+                        "blades" => blades = Some(d.value("blades")?),
+                        "rotation" => rotation = d.number("rotation")?,
+                        "bokeh" => {
+                            let path = self.base_dir.join(d.word("a filename")?);
+                            bokeh = Some(Aperture::from_image(path)?);
+                        },
+                        "position" => position = Some(d.vec3("position")?),
+                        "direction" => direction = Some(d.vec3("direction")?),
+                        "target" => target = Some(d.vec3("target")?),
+                        "up" => up = d.vec3("up")?,
+                        "fov" => fov = d.positive("fov")?,
+                        "near" => near = d.number("near")?,
+                        _ => return Err(d.unknown(property))
+                    }
+                }
+                let position = d.require(position, "position")?;
+                let direction = match (direction, target) {
+                    (Some(direction), None) => direction,
+                    (None, Some(target)) => target - position,
+                    (Some(_), Some(_)) => return Err(d.error("Camera can't have both a direction and a target")),
+                    (None, None) => return Err(d.error("'camera' needs a direction or a target"))
+                };
+                if direction.length_squared() == 0.0 { return Err(d.error("Camera direction can't be zero")); }
+                if direction.normalized().cross(up).length_squared() < 1e-12 {
+                    return Err(d.error("Camera up vector can't be parallel to its direction"));
+                }
+                if fov >= 180.0 { return Err(d.error("fov must be less than 180 degrees")); }
+                let aperture = match (blades, bokeh) {
+                    (None, None) => Aperture::Circle,
+                    (Some(blades), None) if blades >= 3 => Aperture::Polygon { blades: blades, rotation: f64::to_radians(rotation) },
+                    (Some(_), None) => return Err(d.error("An aperture needs at least 3 blades")),
+                    (None, Some(bokeh)) => bokeh,
+                    (Some(_), Some(_)) => return Err(d.error("Camera can't have both blades and a bokeh image"))
+                };
+                let end_pose = if end_position.is_some() || end_direction.is_some() || end_target.is_some() {
+                    let end_position = end_position.unwrap_or(position);
+                    let end_direction = match (end_direction, end_target) {
+                        (Some(direction), None) => direction,
+                        (None, Some(target)) => target - end_position,
+                        (Some(_), Some(_)) => return Err(d.error("Camera can't have both an end direction and an end target")),
+                        (None, None) => direction
+                    };
+                    if end_direction.length_squared() == 0.0 { return Err(d.error("Camera end direction can't be zero")); }
+                    if end_direction.normalized().cross(up).length_squared() < 1e-12 {
+                        return Err(d.error("Camera up vector can't be parallel to its end direction"));
+                    }
+                    Some((end_position, end_direction.normalized()))
+                } else {
+                    None
+                };
+                // Without an explicit focus distance, focus on the target.
+                let focus_distance = match (focus_distance, target) {
+                    (Some(distance), _) => distance,
+                    (None, Some(target)) => (target - position).dot(direction.normalized()),
+                    (None, None) if lens_radius > 0.0 => return Err(d.error("'camera' with an aperture needs a focus distance")),
+                    (None, None) => 1.0
+                };
+                self.camera = Some(CameraDescription {
+                    position: position,
+                    direction: direction.normalized(),
+                    up: up,
+                    fov: fov,
+                    near: near,
+                    lens_radius: lens_radius,
+                    focus_distance: focus_distance,
+                    aperture: aperture,
+                    shutter_open: shutter_open,
+                    shutter_close: shutter_close,
+                    end_pose: end_pose
+                });
+            },
+            "fog" => {
+                if self.scene.medium().is_some() { return Err(d.error("Fog defined twice")); }
+                let (mut absorption, mut scattering, mut g) = (Vec3d::zero(), Vec3d::zero(), 0.0);
+                while let Some(property) = d.property() {
+                    match property {
+                        "absorption" => absorption = d.vec3("absorption")?,
+                        "scattering" => scattering = d.vec3("scattering")?,
+                        "g" => g = d.number("g")?,
+                        _ => return Err(d.unknown(property))
+                    }
+                }
+                if absorption.min_component() < 0.0 || scattering.min_component() < 0.0 {
+                    return Err(d.error("Fog can't have negative absorption or scattering"));
+                }
+                if g <= -1.0 || g >= 1.0 { return Err(d.error("g must be between -1 and 1")); }
+                self.scene.set_medium(Medium::new(absorption, scattering, g));
+            },
+            "texture" => {
+                let name = d.word("a name")?;
+                if self.textures.contains_key(name) {
+                    return Err(d.error(format!("Texture '{}' defined twice", name)));
+                }
+                let texture: Arc<Texture> = match d.word("a type")? {
+                    "checker" => {
+                        let (mut even, mut odd, mut scale) = (Vec3d::one(), Vec3d::zero(), 8.0);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "even" => even = d.vec3("even")?,
+                                "odd" => odd = d.vec3("odd")?,
+                                "scale" => scale = d.positive("scale")?,
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        Arc::new(Checkerboard { even: constant_texture(even), odd: constant_texture(odd), scale: scale })
+                    },
+                    "image" => {
+                        let (mut file, mut wrap, mut linear) = (None, WrapMode::Repeat, false);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "file" => file = Some(self.base_dir.join(d.word("a filename")?)),
+                                "wrap" => {
+                                    let mode = d.word("a wrap mode")?;
+                                    wrap = WrapMode::from_name(mode).ok_or_else(|| {
+                                        d.error(format!("Unknown wrap mode '{}' (expected repeat, clamp or mirror)", mode))
+                                    })?;
+                                },
+                                "linear" => linear = true,
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        let file = d.require(file, "file")?;
+                        Arc::new(if linear { ImageTexture::load_linear(file, wrap)? } else { ImageTexture::load(file, wrap)? })
+                    },
+                    "noise" => {
+                        let (mut low, mut high) = (Vec3d::zero(), Vec3d::one());
+                        let (mut scale, mut octaves, mut seed) = (1.0, 4, 0);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "low" => low = d.vec3("low")?,
+                                "high" => high = d.vec3("high")?,
+                                "scale" => scale = d.positive("scale")?,
+                                "octaves" => octaves = d.value("octaves")?,
+                                "seed" => seed = d.value("seed")?,
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        Arc::new(NoiseTexture { low: low, high: high, scale: scale, octaves: octaves, noise: Perlin::new(seed) })
+                    },
+                    other => return Err(d.error(format!("Unknown texture type '{}'", other)))
+                };
+                self.textures.insert(name.to_string(), texture);
+            },
+            "material" => {
+                let name = d.word("a name")?;
+                if self.materials.contains_key(name) {
+                    return Err(d.error(format!("Material '{}' defined twice", name)));
+                }
+                let kind = d.word("a type")?;
+                if !["diffuse", "specular", "refractive", "conductor", "rough_refractive", "medium"].contains(&kind) {
+                    return Err(d.error(format!("Unknown material type '{}'", kind)));
+                }
+                let rough = kind == "conductor" || kind == "rough_refractive";
+                let dielectric = kind == "refractive" || kind == "rough_refractive";
+                let medium = kind == "medium";
+                // Metals get their colour from their index of refraction, so aren't tinted.
+                let mut colour = if kind == "conductor" { Vec3d::one() } else { Vec3d::new(0.75, 0.75, 0.75) };
+                let (mut texture, mut normal_map, mut emission) = (None, None, Vec3d::zero());
+                let (mut ior, mut absorption) = (1.5, Vec3d::zero());
+                let (mut roughness, mut roughness_texture, mut distribution) = (0.1, None, MicrofacetType::Ggx);
+                let (mut eta, mut k) = (None, None);
+                let (mut scattering, mut g) = (Vec3d::zero(), 0.0);
+                while let Some(property) = d.property() {
+                    match property {
+                        "colour" if !medium => colour = d.vec3("colour")?,
+                        "texture" if !medium => texture = Some(self.texture(d)?),
+                        "normal_map" if !medium => normal_map = Some(NormalMap::Tangent(self.texture(d)?)),
+                        "bump" if !medium => {
+                            let height = self.texture(d)?;
+                            normal_map = Some(NormalMap::Bump(height, d.number("bump height")?));
+                        },
+                        "emission" if !medium => emission = d.vec3("emission")?,
+                        "ior" if dielectric => ior = d.positive("ior")?,
+                        "absorption" if dielectric || medium => absorption = d.vec3("absorption")?,
+                        "scattering" if medium => scattering = d.vec3("scattering")?,
+                        "g" if medium => g = d.number("g")?,
+                        "roughness" if rough => roughness = d.number("roughness")?,
+                        "roughness_texture" if rough => roughness_texture = Some(self.texture(d)?),
+                        "distribution" if rough => distribution = match d.word("a distribution")? {
+                            "ggx" => MicrofacetType::Ggx,
+                            "beckmann" => MicrofacetType::Beckmann,
+                            other => return Err(d.error(format!("Unknown distribution '{}' (expected ggx or beckmann)", other)))
+                        },
+                        "metal" if kind == "conductor" => {
+                            let name = d.word("a metal")?;
+                            let preset = conductor_preset(name)
+                                .ok_or_else(|| d.error(format!("Unknown metal '{}'", name)))?;
+                            eta = Some(preset.eta);
+                            k = Some(preset.k);
+                        },
+                        "eta" if kind == "conductor" => eta = Some(d.vec3("eta")?),
+                        "k" if kind == "conductor" => k = Some(d.vec3("k")?),
+                        _ => return Err(d.unknown(property))
+                    }
+                }
+                if absorption.min_component() < 0.0 {
+                    return Err(d.error("absorption can't be negative"));
+                }
+                if scattering.min_component() < 0.0 {
+                    return Err(d.error("scattering can't be negative"));
+                }
+                if g <= -1.0 || g >= 1.0 {
+                    return Err(d.error("g must be between -1 and 1"));
+                }
+                if !(0.0..=1.0).contains(&roughness) {
+                    return Err(d.error("roughness must be between 0 and 1"));
+                }
+                let distribution = MicrofacetDistribution::new(distribution, roughness);
+                let material: Material = match kind {
+                    "diffuse" => Arc::new(Lambertian),
+                    "specular" => Arc::new(Mirror),
+                    "refractive" => Arc::new(Dielectric::new(ior, absorption)),
+                    "medium" => Arc::new(MediumBoundary::new(Medium::new(absorption, scattering, g))),
+                    "conductor" => {
+                        let (eta, k) = (d.require(eta, "metal or eta")?, d.require(k, "metal or k")?);
+                        let conductor = RoughConductor::new(distribution, eta, k);
+                        match roughness_texture {
+                            Some(texture) => Arc::new(conductor.with_roughness_texture(texture)),
+                            None => Arc::new(conductor)
+                        }
+                    },
+                    _ => {
+                        let dielectric = RoughDielectric::new(distribution, ior, absorption);
+                        match roughness_texture {
+                            Some(texture) => Arc::new(dielectric.with_roughness_texture(texture)),
+                            None => Arc::new(dielectric)
+                        }
+                    }
+                };
+                let named = NamedMaterial {
+                    material: material,
+                    colour: colour,
+                    texture: texture,
+                    normal_map: normal_map,
+                    emission: emission,
+                    is_light: false
+                };
+                self.materials.insert(name.to_string(), named);
+            },
+            "sphere" => {
+                let m = self.material(d)?;
+                let (mut radius, mut centre, mut end_centre) = (None, None, None);
+                while let Some(property) = d.property() {
+                    match property {
+                        "radius" => radius = Some(d.positive("radius")?),
+                        "centre" => centre = Some(d.vec3("centre")?),
+                        "end_centre" => end_centre = Some(d.vec3("end centre")?),
+                        _ => return Err(d.unknown(property))
+                    }
+                }
+                let (radius, centre) = (d.require(radius, "radius")?, d.require(centre, "centre")?);
+                let sphere = Sphere::new(m.material, radius, centre, m.emission, m.colour);
+                let sphere = match end_centre { Some(end) => sphere.with_motion(end), None => sphere };
+                let sphere = match m.texture { Some(texture) => sphere.with_texture(texture), None => sphere };
+                self.scene.add(Box::new(match m.normal_map { Some(map) => sphere.with_normal_map(map), None => sphere }));
+            },
+            "quad" => {
+                let m = self.material(d)?;
+                let quad = self.quad(d, m)?;
+                self.scene.add(Box::new(quad));
+            },
+            "disc" => {
+                let m = self.material(d)?;
+                let disc = self.disc(d, m)?;
+                self.scene.add(Box::new(disc));
+            },
+            "triangle" => {
+                let m = self.material(d)?;
+                let mut vertices = None;
+                while let Some(property) = d.property() {
+                    match property {
+                        "vertices" => vertices = Some([d.vec3("vertex")?, d.vec3("vertex")?, d.vec3("vertex")?]),
+                        _ => return Err(d.unknown(property))
+                    }
+                }
+                let vertices = d.require(vertices, "list of vertices")?;
+                let triangle = Triangle::new(m.material, vertices, m.emission, m.colour);
+                let triangle = match m.texture { Some(texture) => triangle.with_texture(texture), None => triangle };
+                self.scene.add(Box::new(match m.normal_map { Some(map) => triangle.with_normal_map(map), None => triangle }));
+            },
+            "obj" => {
+                let path = self.base_dir.join(d.word("a filename")?);
+                load_obj(&path, &mut self.scene)?;
+            },
+            "mesh" => {
+                let name = d.word("a name")?;
+                if self.meshes.contains_key(name) {
+                    return Err(d.error(format!("Mesh '{}' defined twice", name)));
+                }
+                let mut file = None;
+                while let Some(property) = d.property() {
+                    match property {
+                        "file" => file = Some(self.base_dir.join(d.word("a filename")?)),
+                        _ => return Err(d.unknown(property))
+                    }
+                }
+                let parts = load_obj_meshes(d.require(file, "file")?)?.into_iter()
+                    .map(|mesh| Arc::new(mesh) as Arc<Renderable>)
+                    .collect();
+                self.meshes.insert(name.to_string(), parts);
+            },
+            "instance" => {
+                let name = d.word("a mesh name")?;
+                let parts = self.meshes.get(name).cloned()
+                    .ok_or_else(|| d.error(format!("Unknown mesh '{}'", name)))?;
+                let (mut transform, mut material) = (Transform::identity(), None);
+                let mut keyframes: Vec<Keyframe> = Vec::new();
+                while let Some(property) = d.property() {
+                    let step = match property {
+                        "translate" => {
+                            let translation = d.vec3("translation")?;
+                            if let Some(keyframe) = keyframes.last_mut() {
+                                keyframe.translation = keyframe.translation + translation;
+                                continue;
+                            }
+                            Transform::translate(translation)
+                        },
+                        "rotate" => {
+                            let axis = d.vec3("rotation axis")?;
+                            if axis.length_squared() == 0.0 { return Err(d.error("Rotation axis can't be zero")); }
+                            let angle = d.number("rotation angle")?.to_radians();
+                            if let Some(keyframe) = keyframes.last_mut() {
+                                keyframe.rotation = Quaternion::from_axis_angle(axis, angle) * keyframe.rotation;
+                                continue;
+                            }
+                            Transform::rotate(axis, angle)
+                        },
+                        "scale" => {
+                            let factors = d.vec3("scale")?;
+                            if factors.x * factors.y * factors.z == 0.0 { return Err(d.error("Scale can't be zero")); }
+                            if let Some(keyframe) = keyframes.last_mut() {
+                                keyframe.scale = keyframe.scale * factors;
+                                continue;
+                            }
+                            Transform::scale(factors)
+                        },
+                        "at" => {
+                            // Numbers are always finite, so times can be compared safely.
+                            let time = d.number("keyframe time")?;
+                            let previous = keyframes.last().cloned();
+                            if previous.is_some_and(|k| k.time >= time) {
+                                return Err(d.error("Keyframes must be in order of time"));
+                            }
+                            // Each keyframe starts from where the last one left off.
+                            keyframes.push(Keyframe { time: time, ..previous.unwrap_or(Keyframe::new(time)) });
+                            continue;
+                        },
+                        "material" => {
+                            let m = self.material(d)?;
+                            if m.emission.max_component() > 0.0 || m.normal_map.is_some() {
+                                return Err(d.error("An instance's material can only change its material and colour"));
+                            }
+                            material = Some(m);
+                            continue;
+                        },
+                        _ => return Err(d.unknown(property))
+                    };
+                    transform = step * transform;
+                }
+                let animation = if keyframes.is_empty() { None } else { Some(AnimatedTransform::new(keyframes)) };
+                for part in parts {
+                    let instance = Instance::new(part, transform);
+                    let instance = match animation { Some(ref a) => instance.with_animation(a.clone()), None => instance };
+                    self.scene.add(Box::new(match material {
+                        Some(ref m) => instance.with_material(m.material.clone())
+                            .with_texture(m.texture.clone().unwrap_or_else(|| constant_texture(m.colour))),
+                        None => instance
+                    }));
+                }
+            },
+            "light" => {
+                match d.word("a shape")? {
+                    "sphere" => {
+                        let (mut radius, mut centre, mut end_centre, mut emission) = (None, None, None, None);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "radius" => radius = Some(d.positive("radius")?),
+                                "centre" => centre = Some(d.vec3("centre")?),
+                                "end_centre" => end_centre = Some(d.vec3("end centre")?),
+                                "emission" => emission = Some(d.vec3("emission")?),
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        let (radius, centre) = (d.require(radius, "radius")?, d.require(centre, "centre")?);
+                        let emission = d.require(emission, "emission")?;
+                        let sphere = Sphere::new(Arc::new(Lambertian), radius, centre, emission, Vec3d::zero());
+                        self.scene.add(Box::new(match end_centre { Some(end) => sphere.with_motion(end), None => sphere }));
+                    },
+                    "quad" => {
+                        let quad = self.quad(d, NamedMaterial::light())?;
+                        self.scene.add(Box::new(quad));
+                    },
+                    "disc" => {
+                        let disc = self.disc(d, NamedMaterial::light())?;
+                        self.scene.add(Box::new(disc));
+                    },
+                    "point" => {
+                        let (mut position, mut intensity, mut falloff) = (None, None, 2.0);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "position" => position = Some(d.vec3("position")?),
+                                "intensity" => intensity = Some(d.vec3("intensity")?),
+                                "falloff" => falloff = d.number("falloff")?,
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        let (position, intensity) = (d.require(position, "position")?, d.require(intensity, "intensity")?);
+                        self.scene.add_light(Box::new(PointLight::new(position, intensity).with_falloff(falloff)));
+                    },
+                    "spot" => {
+                        let (mut position, mut direction, mut target, mut intensity) = (None, None, None, None);
+                        let (mut cone, mut edge, mut falloff) = (None, 0.0, 2.0);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "position" => position = Some(d.vec3("position")?),
+                                "direction" => direction = Some(d.vec3("direction")?),
+                                "target" => target = Some(d.vec3("target")?),
+                                "intensity" => intensity = Some(d.vec3("intensity")?),
+                                "cone" => cone = Some(d.positive("cone")?),
+                                "edge" => edge = d.number("edge")?,
+                                "falloff" => falloff = d.number("falloff")?,
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        let (position, intensity) = (d.require(position, "position")?, d.require(intensity, "intensity")?);
+                        let cone = d.require(cone, "cone angle")?;
+                        let direction = match (direction, target) {
+                            (Some(direction), None) => direction,
+                            (None, Some(target)) => target - position,
+                            (Some(_), Some(_)) => return Err(d.error("Spot light can't have both a direction and a target")),
+                            (None, None) => return Err(d.error("'light spot' needs a direction or target"))
+                        };
+                        if direction.length_squared() == 0.0 { return Err(d.error("Spot light has no direction")); }
+                        if cone >= 180.0 || edge < 0.0 || edge > cone {
+                            return Err(d.error("Spot light cone must be under 180 degrees, with an edge no wider than it"));
+                        }
+                        let spot = SpotLight::new(position, direction, intensity, cone, edge).with_falloff(falloff);
+                        self.scene.add_light(Box::new(spot));
+                    },
+                    "directional" => {
+                        let (mut direction, mut irradiance) = (None, None);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "direction" => direction = Some(d.vec3("direction")?),
+                                "irradiance" => irradiance = Some(d.vec3("irradiance")?),
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        let (direction, irradiance) = (d.require(direction, "direction")?, d.require(irradiance, "irradiance")?);
+                        if direction.length_squared() == 0.0 { return Err(d.error("Directional light has no direction")); }
+                        self.scene.add_light(Box::new(DirectionalLight::new(direction, irradiance)));
+                    },
+                    "environment" => {
+                        let (mut image, mut rotation, mut intensity) = (None, 0.0, 1.0);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "image" => image = Some(self.base_dir.join(d.word("a filename")?)),
+                                "rotation" => rotation = d.number("rotation")?,
+                                "intensity" => intensity = d.number("intensity")?,
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        let image = d.require(image, "image")?;
+                        self.scene.add_light(Box::new(EnvironmentLight::load(image, rotation, intensity)?));
+                    },
+                    "sky" => {
+                        let (mut elevation, mut azimuth, mut turbidity) = (None, 0.0, 3.0);
+                        let (mut intensity, mut resolution, mut sun) = (1.0, 512, true);
+                        while let Some(property) = d.property() {
+                            match property {
+                                "elevation" => elevation = Some(d.number("elevation")?),
+                                "azimuth" => azimuth = d.number("azimuth")?,
+                                "turbidity" => turbidity = d.number("turbidity")?,
+                                "intensity" => intensity = d.number("intensity")?,
+                                "resolution" => resolution = d.value("resolution")?,
+                                "no_sun" => sun = false,
+                                _ => return Err(d.unknown(property))
+                            }
+                        }
+                        let elevation = d.require(elevation, "elevation")?;
+                        if !(0.0..=90.0).contains(&elevation) {
+                            return Err(d.error("Sun elevation must be between 0 and 90 degrees"));
+                        }
+                        if !(1.7..=10.0).contains(&turbidity) {
+                            return Err(d.error("Sky turbidity must be between 1.7 and 10"));
+                        }
+                        // The image is half as tall as it is wide, so it needs at least one row.
+                        if resolution < 2 {
+                            return Err(d.error("Sky resolution must be a whole number of at least 2"));
+                        }
+                        let (sky, sun_light) = PreethamSky::new(sun_direction(elevation, azimuth), turbidity)
+                            .lights(resolution, intensity);
+                        self.scene.add_light(Box::new(sky));
+                        if sun { self.scene.add_light(Box::new(sun_light)); }
+                    },
+                    other => return Err(d.error(format!("Unknown light type '{}'", other)))
+                }
+            },
+            other => return Err(d.error(format!("Unknown directive '{}'", other)))
+        }
+        if let Some(extra) = d.property() {
+            return Err(d.error(format!("Unexpected '{}'", extra)));
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_scene<R: BufRead>(reader: R, file: &str, base_dir: &Path) -> Result<SceneDescription> {
+    let mut parser = Parser {
+        base_dir: base_dir,
+        scene: Scene::new(),
+        camera: None,
+        settings: RenderSettings::new(),
+        materials: HashMap::new(),
+        textures: HashMap::new(),
+        meshes: HashMap::new()
+    };
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| LoadError::IoError(file.to_string(), e))?;
+        let content = line.split('#').next().unwrap();
+        let mut tokens = content.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue
+        };
+        let mut directive = Directive { file: file, line: line_no + 1, keyword: keyword, tokens: tokens };
+        parser.parse_line(&mut directive)?;
+    }
+    let camera = parser.camera.ok_or_else(|| {
+        LoadError::InvalidError { file: file.to_string(), message: "No camera defined".to_string() }
+    })?;
+    let mut scene = parser.scene;
+    scene.build_bvh();
+    scene.build_light_distribution();
+    Ok(SceneDescription { scene: scene, camera: camera, settings: parser.settings })
+}
+
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<SceneDescription> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let reader = BufReader::new(File::open(path).map_err(|e| LoadError::IoError(name.clone(), e))?);
+    parse_scene(reader, &name, path.parent().unwrap_or(Path::new(".")))
+}
+
+#[test]
+fn parses_scene() {
+    let text = "# A test\nrender width 32 height 16\ncamera direction 0 0 -2 position 1 2 3\n\
+                material white diffuse colour 1 1 1\nsphere white centre 0 0 0 radius 1\n\
+                texture checks checker scale 4\nmaterial floor diffuse texture checks bump checks 0.01\n\
+                quad floor corner -1 0 -1 u 2 0 0 v 0 0 2\n\
+                light sphere radius 1 centre 0 5 0 emission 4 4 4\n\
+                fog scattering 0.01 0.01 0.01\nmaterial smoke medium scattering 1 1 1 g 0.5\n";
+    let description = parse_scene(text.as_bytes(), "test.scene", Path::new(".")).unwrap();
+    assert_eq!(description.settings.width, 32);
+    assert_eq!(description.settings.samples, 4);
+    assert_eq!(description.camera.direction.z, -1.0);
+    assert_eq!(description.camera.position.y, 2.0);
+    assert_eq!((description.camera.shutter_open, description.camera.shutter_close), (0.0, 1.0));
+    assert!(description.camera.end_pose.is_none());
+    assert!(description.scene.medium().is_some());
+}
+
+#[test]
+fn reports_errors_with_locations() {
+    let check_message = |text: &str, expected_line: usize, expected_message: &str| {
+        match parse_scene(text.as_bytes(), "test.scene", Path::new(".")) {
+            Err(LoadError::ParseError { line, message, .. }) => {
+                assert_eq!(line, expected_line);
+                assert!(message.contains(expected_message), "unexpected message '{}'", message);
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("expected an error")
+        }
+    };
+    let check = |text: &str, expected_line: usize| check_message(text, expected_line, "");
+    check("camera position 0 0 0 direction 0 0 1\nsphere missing radius 1 centre 0 0 0\n", 2);
+    check("\n\nmaterial a diffuse colour 1 1\n", 3);
+    check("material a diffuse\nsphere a radius -1 centre 0 0 0\n", 2);
+    check("render width 10 bogus 3\n", 1);
+    check("render min_depth 3 max_depth 2\n", 1);
+    check("material a conductor metal unobtainium\n", 1);
+    check("material a conductor roughness 0.5\n", 1);
+    check("camera position 0 0 0 target 0 5 0\n", 1);
+    check("texture a checker\nmaterial b diffuse texture c\n", 2);
+    check("texture a image file x.png wrap sideways\n", 1);
+    check("texture a checker\nmaterial b conductor metal gold roughness_texture c\n", 2);
+    check("texture a checker\nmaterial b diffuse roughness_texture a\n", 2);
+    check("texture a checker\nmaterial b diffuse bump a\n", 2);
+    check("material a diffuse\ninstance teapot material a\n", 2);
+    check("material a diffuse\nsphere a radius 1 centre 0 NaN 0\n", 2);
+    check("camera position 0 0 0 direction 0 0 1 fov inf\n", 1);
+    check("camera position 0 0 0 direction 0 0 1 shutter 0.5 0.25\n", 1);
+    check("material smoke medium scattering 1 1 1 colour 1 0 0\n", 1);
+    check_message("fog g 0.5\nfog g 0.5\n", 2, "defined twice");
+    check_message("fog g 1\n", 1, "g must be between");
+    check("light sky elevation 30\nlight sky elevation 30 resolution 0.5\n", 2);
+    check("light sky elevation 30 resolution 1\n", 1);
+    check("camera position 0 0 0 direction 0 0 1 end_position 0 0 0 end_direction 0 1 0\n", 1);
+    match parse_scene("render width 10\n".as_bytes(), "test.scene", Path::new(".")) {
+        Err(LoadError::InvalidError { .. }) => {},
+        _ => panic!("a scene without a camera should be invalid")
+    }
+}
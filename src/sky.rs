@@ -0,0 +1,125 @@
+// The Preetham, Shirley and Smits analytic daylight model ("A Practical Analytic Model for
+// Daylight", 1999). The sky's luminance and chromaticity each follow a Perez distribution whose
+// coefficients depend on the atmosphere's turbidity, relative to their values at the zenith.
+// Turbidity runs from about 2 (very clear) to 10 (hazy); the model isn't valid outside that.
+//
+// Radiance is in kcd/m^2. The sky is baked into an environment image so it can be sampled like
+// any other, and the sun is a separate small disc light.
+use light::{EnvironmentLight, SunLight};
+use math::Vec3d;
+
+use std::f64::consts::PI;
+
+// The sun's angular radius, in radians.
+const SUN_RADIUS: f64 = 0.00465;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PreethamSky {
+    // Towards the sun.
+    sun: Vec3d,
+    turbidity: f64,
+    // Perez coefficients A to E for Y, x and y.
+    perez: [[f64; 5]; 3],
+    // Y, x and y at the zenith, divided by their Perez functions there.
+    zenith: [f64; 3]
+}
+
+// Elevation is in degrees above the horizon, and azimuth in degrees clockwise from -z (seen from
+// above) towards +x.
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vec3d {
+    let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+    Vec3d::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos())
+}
+
+fn perez(coefficients: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+}
+
+fn xyy_to_rgb(big_y: f64, x: f64, y: f64) -> Vec3d {
+    let big_x = x / y * big_y;
+    let big_z = (1.0 - x - y) / y * big_y;
+    Vec3d::new(
+        3.2406 * big_x - 1.5372 * big_y - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * big_y + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * big_y + 1.0570 * big_z)
+}
+
+impl PreethamSky {
+    pub fn new(sun: Vec3d, turbidity: f64) -> PreethamSky {
+        let t = turbidity;
+        let sun = sun.normalized();
+        // Below the horizon the model falls apart, so keep the sun just above it.
+        let theta_s = sun.y.max(0.01).acos();
+        let coefficients = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529]
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let (th, th2, th3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let zenith_x = t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_yc = t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+        let mut zenith = [zenith_y.max(0.0), zenith_x, zenith_yc];
+        for i in 0..3 {
+            zenith[i] /= perez(&coefficients[i], 1.0, theta_s);
+        }
+        PreethamSky { sun: Vec3d::new(sun.x, theta_s.cos(), sun.z).normalized(), turbidity: t, perez: coefficients, zenith: zenith }
+    }
+
+    // The sky's radiance in a given direction, not including the sun itself. The ground is
+    // black; scenes are expected to have their own.
+    pub fn radiance(&self, direction: Vec3d) -> Vec3d {
+        if direction.y <= 0.0 { return Vec3d::zero(); }
+        // The Perez function blows up right at the horizon.
+        let cos_theta = direction.y.max(0.01);
+        let gamma = direction.dot(self.sun).clamp(-1.0, 1.0).acos();
+        let value = |i: usize| self.zenith[i] * perez(&self.perez[i], cos_theta, gamma);
+        let rgb = xyy_to_rgb(value(0), value(1), value(2));
+        Vec3d::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0))
+    }
+
+    // The radiance of the sun's disc, after Rayleigh and aerosol scattering on its way through
+    // the atmosphere, at roughly 650nm, 550nm and 450nm.
+    pub fn sun_radiance(&self) -> Vec3d {
+        let theta_deg = self.sun.y.acos().to_degrees();
+        // Relative optical mass, allowing for the curvature of the atmosphere.
+        let mass = 1.0 / (self.sun.y + 0.15 * (93.885 - theta_deg).powf(-1.253));
+        let beta = 0.04608365822050 * self.turbidity - 0.04586025928522;
+        let transmittance = |lambda: f64| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * mass).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * mass).exp();
+            rayleigh * aerosol
+        };
+        // The sun's luminance above the atmosphere.
+        Vec3d::new(transmittance(0.65), transmittance(0.55), transmittance(0.45)) * 1.6e6
+    }
+
+    // An environment light for the sky of the given width, and the sun, both scaled by
+    // `intensity`.
+    pub fn lights(&self, width: usize, intensity: f64) -> (EnvironmentLight, SunLight) {
+        let sky = EnvironmentLight::from_function(width, (width / 2).max(1), |d| self.radiance(d) * intensity);
+        let sun = SunLight::new(self.sun, SUN_RADIUS, self.sun_radiance() * intensity);
+        (sky, sun)
+    }
+}
+
+#[test]
+fn sky_is_brightest_near_the_sun() {
+    let sky = PreethamSky::new(sun_direction(30.0, 90.0), 3.0);
+    let near = sky.radiance(sun_direction(35.0, 90.0));
+    let away = sky.radiance(sun_direction(35.0, 270.0));
+    assert!(near.y > away.y * 2.0);
+    // A clear sky is blue overhead.
+    let zenith = sky.radiance(Vec3d::new(0.0, 1.0, 0.0));
+    assert!(zenith.z > zenith.x);
+    assert_eq!(sky.radiance(Vec3d::new(0.0, -1.0, 0.0)).y, 0.0);
+    // And the sun is far brighter than the sky, and redder when low.
+    let sun = sky.sun_radiance();
+    assert!(sun.y > zenith.y * 1e4 && sun.x > sun.z);
+}
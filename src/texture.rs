@@ -0,0 +1,322 @@
+// Colours that vary over a surface. Textures are evaluated at each hit, given its surface
+// coordinates (u, v), each nominally in [0, 1], and its position for solid textures that fill
+// space rather than being wrapped around the surface.
+use error::LoadError;
+use hdr::{load_hdr_image, HdrFormat, ExrPixelType};
+use math::{Onb, Vec3d, F64Rng};
+use renderable::Hit;
+use tonemap::srgb_decode;
+
+use image;
+use rand::{SeedableRng, XorShiftRng};
+use std::path::Path;
+use std::sync::Arc;
+
+pub trait Texture: Send + Sync {
+    fn value(&self, uv: (f64, f64), pos: Vec3d) -> Vec3d;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantTexture(pub Vec3d);
+
+impl Texture for ConstantTexture {
+    fn value(&self, _uv: (f64, f64), _pos: Vec3d) -> Vec3d {
+        self.0
+    }
+}
+
+pub fn constant_texture(colour: Vec3d) -> Arc<Texture> {
+    Arc::new(ConstantTexture(colour))
+}
+
+// What happens to lookups outside [0, 1].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror
+}
+
+impl WrapMode {
+    pub fn from_name(name: &str) -> Option<WrapMode> {
+        match name {
+            "repeat" => Some(WrapMode::Repeat),
+            "clamp" => Some(WrapMode::Clamp),
+            "mirror" => Some(WrapMode::Mirror),
+            _ => None
+        }
+    }
+
+    fn wrap(self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+        let i = match self {
+            WrapMode::Repeat => ((i % n) + n) % n,
+            WrapMode::Clamp => i.clamp(0, n - 1),
+            WrapMode::Mirror => {
+                let m = ((i % (2 * n)) + 2 * n) % (2 * n);
+                if m >= n { 2 * n - 1 - m } else { m }
+            }
+        };
+        i as usize
+    }
+}
+
+// An image stretched over [0, 1]^2, with v = 0 at the bottom, filtered bilinearly.
+pub struct ImageTexture {
+    pixels: Vec<Vec3d>,
+    width: usize,
+    height: usize,
+    wrap: WrapMode
+}
+
+impl ImageTexture {
+    // `rows` is linear RGB, top row first.
+    pub fn new(rows: Vec<Vec<Vec3d>>, wrap: WrapMode) -> ImageTexture {
+        assert!(!rows.is_empty() && !rows[0].is_empty(), "Can't build a texture from an empty image");
+        let (width, height) = (rows[0].len(), rows.len());
+        assert!(rows.iter().all(|row| row.len() == width), "Texture image rows must all be the same length");
+        ImageTexture {
+            pixels: rows.into_iter().flat_map(|row| row.into_iter()).collect(),
+            width: width,
+            height: height,
+            wrap: wrap
+        }
+    }
+
+    // Loads a .hdr or .pfm as it is, or any other image the image crate can read as sRGB.
+    pub fn load<P: AsRef<Path>>(path: P, wrap: WrapMode) -> Result<ImageTexture, LoadError> {
+        ImageTexture::read(path.as_ref(), wrap, true)
+    }
+
+    // Loads an image holding data rather than colours, like a normal map, without decoding sRGB.
+    pub fn load_linear<P: AsRef<Path>>(path: P, wrap: WrapMode) -> Result<ImageTexture, LoadError> {
+        ImageTexture::read(path.as_ref(), wrap, false)
+    }
+
+    fn read(path: &Path, wrap: WrapMode, srgb: bool) -> Result<ImageTexture, LoadError> {
+        let name = path.display().to_string();
+        if HdrFormat::from_filename(&name, ExrPixelType::Float).is_some() {
+            return Ok(ImageTexture::new(load_hdr_image(path)?, wrap));
+        }
+        let image = image::open(path)
+            .map_err(|e| LoadError::InvalidError { file: name.clone(), message: e.to_string() })?
+            .to_rgb();
+        if image.width() == 0 || image.height() == 0 {
+            return Err(LoadError::InvalidError { file: name, message: "Image is empty".to_string() });
+        }
+        let decode = |c: u8| if srgb { srgb_decode(c as f64 / 255.0) } else { c as f64 / 255.0 };
+        let rows = (0..image.height()).map(|y| {
+            (0..image.width()).map(|x| {
+                let p = image.get_pixel(x, y);
+                Vec3d::new(decode(p.data[0]), decode(p.data[1]), decode(p.data[2]))
+            }).collect()
+        }).collect();
+        Ok(ImageTexture::new(rows, wrap))
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vec3d {
+        self.pixels[self.wrap.wrap(y, self.height) * self.width + self.wrap.wrap(x, self.width)]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, uv: (f64, f64), _pos: Vec3d) -> Vec3d {
+        // Texel centres are at half-integer coordinates.
+        let x = uv.0 * self.width as f64 - 0.5;
+        let y = (1.0 - uv.1) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        self.texel(x0, y0) * ((1.0 - fx) * (1.0 - fy)) + self.texel(x0 + 1, y0) * (fx * (1.0 - fy))
+            + self.texel(x0, y0 + 1) * ((1.0 - fx) * fy) + self.texel(x0 + 1, y0 + 1) * (fx * fy)
+    }
+}
+
+// Alternating squares of two textures, `scale` of them across [0, 1] in each direction.
+pub struct Checkerboard {
+    pub even: Arc<Texture>,
+    pub odd: Arc<Texture>,
+    pub scale: f64
+}
+
+impl Texture for Checkerboard {
+    fn value(&self, uv: (f64, f64), pos: Vec3d) -> Vec3d {
+        let parity = ((uv.0 * self.scale).floor() + (uv.1 * self.scale).floor()) as i64;
+        if parity % 2 == 0 { self.even.value(uv, pos) } else { self.odd.value(uv, pos) }
+    }
+}
+
+// Ken Perlin's improved gradient noise: smooth, with features about a unit apart, and values
+// roughly in [-1, 1].
+pub struct Perlin {
+    permutation: Vec<usize>
+}
+
+impl Perlin {
+    pub fn new(seed: u32) -> Perlin {
+        let mut rng = XorShiftRng::from_seed([seed, 0x9e37_79b9, 0x7f4a_7c15, 0x2545_f491]);
+        let mut permutation: Vec<usize> = (0..256).collect();
+        for i in (1..256).rev() {
+            let j = ((rng.next() * (i + 1) as f64) as usize).min(i);
+            permutation.swap(i, j);
+        }
+        let repeated = permutation.clone();
+        permutation.extend(repeated);
+        Perlin { permutation: permutation }
+    }
+
+    fn gradient(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+        // Dot products with the twelve vectors from the centre of a cube to its edges.
+        let h = hash & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    }
+
+    pub fn noise(&self, p: Vec3d) -> f64 {
+        let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (xi, yi, zi) = ((fx as i64 & 255) as usize, (fy as i64 & 255) as usize, (fz as i64 & 255) as usize);
+        let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let perm = &self.permutation;
+        let a = perm[xi] + yi;
+        let (aa, ab) = (perm[a] + zi, perm[a + 1] + zi);
+        let b = perm[xi + 1] + yi;
+        let (ba, bb) = (perm[b] + zi, perm[b + 1] + zi);
+        lerp(w,
+             lerp(v, lerp(u, Perlin::gradient(perm[aa], x, y, z), Perlin::gradient(perm[ba], x - 1.0, y, z)),
+                     lerp(u, Perlin::gradient(perm[ab], x, y - 1.0, z), Perlin::gradient(perm[bb], x - 1.0, y - 1.0, z))),
+             lerp(v, lerp(u, Perlin::gradient(perm[aa + 1], x, y, z - 1.0), Perlin::gradient(perm[ba + 1], x - 1.0, y, z - 1.0)),
+                     lerp(u, Perlin::gradient(perm[ab + 1], x, y - 1.0, z - 1.0),
+                          Perlin::gradient(perm[bb + 1], x - 1.0, y - 1.0, z - 1.0))))
+    }
+
+    // Fractal Brownian motion: octaves of noise, each at twice the frequency and half the
+    // amplitude of the last.
+    pub fn fbm(&self, p: Vec3d, octaves: u32) -> f64 {
+        let (mut sum, mut amplitude, mut frequency) = (0.0, 1.0, 1.0);
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(p * frequency);
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum
+    }
+}
+
+// A solid texture blending between two colours with fBm noise of the object's position.
+pub struct NoiseTexture {
+    pub low: Vec3d,
+    pub high: Vec3d,
+    // Features are about 1 / scale apart.
+    pub scale: f64,
+    pub octaves: u32,
+    pub noise: Perlin
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _uv: (f64, f64), pos: Vec3d) -> Vec3d {
+        let t = (0.5 + 0.5 * self.noise.fbm(pos * self.scale, self.octaves)).clamp(0.0, 1.0);
+        self.low * (1.0 - t) + self.high * t
+    }
+}
+
+// Detail finer than the geometry, added by tilting the shading normal rather than moving the
+// surface.
+#[derive(Clone)]
+pub enum NormalMap {
+    // A tangent space normal map, whose colours in [0, 1] encode a normal with x along dpdu, y
+    // along dpdv and z out of the surface. Images should be loaded linearly.
+    Tangent(Arc<Texture>),
+    // A height field, raising the surface by `scale` times the texture's luminance.
+    Bump(Arc<Texture>, f64)
+}
+
+impl NormalMap {
+    // The hit's new shading normal. It's kept on the same side of the surface as the true
+    // normal, or it could face away from directions that can really see the surface.
+    pub fn shading_normal(&self, hit: &Hit) -> Vec3d {
+        let n = hit.normal;
+        let perturbed = match *self {
+            NormalMap::Tangent(ref texture) => {
+                let t = texture.value(hit.uv, hit.pos) * 2.0 - Vec3d::one();
+                let frame = Onb::from_w_u(n, hit.dpdu);
+                // Mirrored texture coordinates flip the v axis.
+                let y = if frame.v.dot(hit.dpdv) < 0.0 { -t.y } else { t.y };
+                frame.to_world(Vec3d::new(t.x, y, t.z))
+            },
+            NormalMap::Bump(ref texture, scale) => {
+                // Differentiate the height numerically, then tilt the tangents by it.
+                const DELTA: f64 = 1e-3;
+                let (u, v) = hit.uv;
+                let height = |uv: (f64, f64), pos: Vec3d| texture.value(uv, pos).luminance() * scale;
+                let base = height(hit.uv, hit.pos);
+                let dhdu = (height((u + DELTA, v), hit.pos + hit.dpdu * DELTA) - base) / DELTA;
+                let dhdv = (height((u, v + DELTA), hit.pos + hit.dpdv * DELTA) - base) / DELTA;
+                let bumped = (hit.dpdu + n * dhdu).cross(hit.dpdv + n * dhdv);
+                if bumped.dot(n) < 0.0 { bumped.neg() } else { bumped }
+            }
+        };
+        if perturbed.length_squared() > 0.0 && perturbed.dot(hit.geometric_normal) > 0.0 {
+            perturbed.normalized()
+        } else {
+            n
+        }
+    }
+}
+
+#[test]
+fn textures() {
+    let rows = vec![vec![Vec3d::new(1.0, 0.0, 0.0), Vec3d::new(0.0, 1.0, 0.0)],
+                    vec![Vec3d::new(0.0, 0.0, 1.0), Vec3d::new(1.0, 1.0, 1.0)]];
+    let image = ImageTexture::new(rows.clone(), WrapMode::Clamp);
+    // Texel centres come through exactly, v = 0 being the bottom row...
+    assert_eq!(image.value((0.25, 0.75), Vec3d::zero()).x, 1.0);
+    assert_eq!(image.value((0.75, 0.25), Vec3d::zero()).y, 1.0);
+    // ...and halfway between them is an even blend.
+    assert!((image.value((0.5, 0.5), Vec3d::zero()).x - 0.5).abs() < 1e-9);
+    // Wrapping around from the left edge blends with the right edge.
+    let repeat = ImageTexture::new(rows, WrapMode::Repeat);
+    assert!((repeat.value((0.0, 0.75), Vec3d::zero()).y - 0.5).abs() < 1e-9);
+    assert_eq!(WrapMode::Mirror.wrap(-1, 4), 0);
+    assert_eq!(WrapMode::Mirror.wrap(5, 4), 2);
+
+    let checks = Checkerboard { even: constant_texture(Vec3d::one()), odd: constant_texture(Vec3d::zero()), scale: 4.0 };
+    assert_eq!(checks.value((0.1, 0.1), Vec3d::zero()).x, 1.0);
+    assert_eq!(checks.value((0.3, 0.1), Vec3d::zero()).x, 0.0);
+
+    let perlin = Perlin::new(1);
+    // Noise vanishes at lattice points and is continuous between them.
+    assert_eq!(perlin.noise(Vec3d::new(3.0, 1.0, -2.0)), 0.0);
+    let p = Vec3d::new(0.3, 0.7, 1.2);
+    assert!((perlin.noise(p) - perlin.noise(p + Vec3d::new(1e-6, 0.0, 0.0))).abs() < 1e-4);
+    assert!(perlin.fbm(p, 4).abs() < 2.0);
+}
+
+#[test]
+fn normal_maps() {
+    use geometry::{Quad, Ray};
+    use material::Lambertian;
+    use renderable::Renderable;
+    let quad = |map: NormalMap| {
+        Quad::new(Arc::new(Lambertian), Vec3d::zero(), Vec3d::new(1.0, 0.0, 0.0), Vec3d::new(0.0, 0.0, -1.0),
+                  Vec3d::zero(), Vec3d::one()).with_normal_map(map)
+    };
+    let ray = Ray::new(Vec3d::new(0.5, 1.0, -0.5), Vec3d::new(0.0, -1.0, 0.0));
+    // A flat normal map leaves the normal alone, and a tilted one tilts it along dpdu.
+    let flat = quad(NormalMap::Tangent(constant_texture(Vec3d::new(0.5, 0.5, 1.0))));
+    assert!((flat.get_hit(&ray, 1.0).normal - Vec3d::new(0.0, 1.0, 0.0)).length() < 1e-9);
+    let tilted = quad(NormalMap::Tangent(constant_texture(Vec3d::new(1.0, 0.5, 1.0))));
+    let normal = tilted.get_hit(&ray, 1.0).normal;
+    assert!((normal - Vec3d::new(1.0, 1.0, 0.0).normalized()).length() < 1e-9);
+    // One pointing into the surface is ignored.
+    let under = quad(NormalMap::Tangent(constant_texture(Vec3d::new(1.0, 0.5, 0.0))));
+    assert_eq!(under.get_hit(&ray, 1.0).normal.y, 1.0);
+    // A height rising along u leans the normal back the other way.
+    let ramp = ImageTexture::new(vec![vec![Vec3d::zero(), Vec3d::one()]], WrapMode::Clamp);
+    let bumped = quad(NormalMap::Bump(Arc::new(ramp), 0.1));
+    let normal = bumped.get_hit(&ray, 1.0).normal;
+    assert!((normal - Vec3d::new(-0.2, 1.0, 0.0).normalized()).length() < 1e-6);
+}
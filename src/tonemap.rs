@@ -0,0 +1,119 @@
+// Turning linear, unbounded radiance into displayable 8-bit values: an exposure adjustment,
+// a tone curve to squeeze the dynamic range into [0, 1], then a display transfer function.
+use math::Vec3d;
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneCurve {
+    // Just clip anything over 1.
+    Clamp,
+    // Reinhard's L / (1 + L), applied to luminance to preserve hue.
+    Reinhard,
+    // John Hable's filmic curve from Uncharted 2.
+    Filmic,
+    // Krzysztof Narkowicz's fit of the ACES reference rendering transform.
+    Aces
+}
+
+impl FromStr for ToneCurve {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ToneCurve, String> {
+        match s {
+            "clamp" => Ok(ToneCurve::Clamp),
+            "reinhard" => Ok(ToneCurve::Reinhard),
+            "filmic" => Ok(ToneCurve::Filmic),
+            "aces" => Ok(ToneCurve::Aces),
+            _ => Err(format!("Unknown tone curve '{}' (expected clamp, reinhard, filmic or aces)", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferFunction {
+    Srgb,
+    Gamma(f64)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ToneMapper {
+    // Exposure adjustment in stops: each stop doubles the brightness.
+    pub exposure: f64,
+    pub curve: ToneCurve,
+    pub transfer: TransferFunction
+}
+
+fn hable(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn aces(x: f64) -> f64 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    (x * (a * x + b)) / (x * (c * x + d) + e)
+}
+
+pub fn srgb_encode(v: f64) -> f64 {
+    if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+pub fn srgb_decode(v: f64) -> f64 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+impl ToneMapper {
+    pub fn new(curve: ToneCurve, exposure: f64, transfer: TransferFunction) -> ToneMapper {
+        ToneMapper { exposure: exposure, curve: curve, transfer: transfer }
+    }
+
+    // Applies exposure and the tone curve, giving linear values in [0, 1].
+    pub fn tone_map(&self, radiance: Vec3d) -> Vec3d {
+        let v = radiance.max(Vec3d::zero()) * 2f64.powf(self.exposure);
+        let mapped = match self.curve {
+            ToneCurve::Clamp => v,
+            ToneCurve::Reinhard => {
+                let l = v.luminance();
+                if l > 0.0 { v * (1.0 / (1.0 + l)) } else { v }
+            },
+            ToneCurve::Filmic => {
+                const WHITE: f64 = 11.2;
+                // Hable's curve expects a 2x exposure bias.
+                let scale = 1.0 / hable(WHITE);
+                Vec3d::new(hable(2.0 * v.x) * scale, hable(2.0 * v.y) * scale, hable(2.0 * v.z) * scale)
+            },
+            ToneCurve::Aces => Vec3d::new(aces(v.x), aces(v.y), aces(v.z))
+        };
+        mapped.clamp()
+    }
+
+    // Maps linear radiance all the way to 8-bit display values.
+    pub fn to_rgb8(&self, radiance: Vec3d) -> [u8; 3] {
+        let v = self.tone_map(radiance);
+        let encode = |c: f64| {
+            let c = match self.transfer {
+                TransferFunction::Srgb => srgb_encode(c),
+                TransferFunction::Gamma(gamma) => c.powf(1.0 / gamma)
+            };
+            (c * 255.0 + 0.5) as u8
+        };
+        [encode(v.x), encode(v.y), encode(v.z)]
+    }
+}
+
+#[test]
+fn tone_curves_stay_in_range() {
+    let curves = [ToneCurve::Clamp, ToneCurve::Reinhard, ToneCurve::Filmic, ToneCurve::Aces];
+    for &curve in curves.iter() {
+        let mapper = ToneMapper::new(curve, 0.0, TransferFunction::Srgb);
+        assert_eq!(mapper.to_rgb8(Vec3d::zero()), [0, 0, 0]);
+        let mut previous = 0.0;
+        for i in 1..100 {
+            let v = mapper.tone_map(Vec3d::one() * (i as f64 * 0.25)).x;
+            assert!(v >= previous && v <= 1.0, "{:?} isn't monotonic", curve);
+            previous = v;
+        }
+    }
+    // One stop of exposure doubles the input.
+    let mapper = ToneMapper::new(ToneCurve::Clamp, 1.0, TransferFunction::Gamma(1.0));
+    assert_eq!(mapper.to_rgb8(Vec3d::new(0.25, 0.5, 1.0)), [128, 255, 255]);
+}